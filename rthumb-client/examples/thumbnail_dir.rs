@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rthumb_client::{Client, ThumbResult};
+use rthumbd::dbus::{MediaRef, ThumbFlavor};
+
+// Usage: thumbnail_dir DIRECTORY [FLAVOR]
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let dir = args.next().ok_or(anyhow!("missing directory"))?;
+    let flavor = match args.next() {
+        Some(flavor) => {
+            ThumbFlavor::try_from(flavor.as_str()).map_err(|_| anyhow!("invalid flavor"))?
+        }
+        None => ThumbFlavor::Normal,
+    };
+    let mut medias = Vec::new();
+    for entry in std::fs::read_dir(std::path::absolute(dir)?)? {
+        let path = entry?.path();
+        if path.is_file() {
+            medias.push(MediaRef {
+                uri: url_from_path(&path)?,
+                // Leaves it to the daemon to figure out.
+                mime_type: String::new(),
+            });
+        }
+    }
+
+    futures_lite::future::block_on(async {
+        let client = Client::connect()
+            .await?
+            .with_timeout(Duration::from_secs(30));
+        let mut request = client.queue(&medias, flavor).await?;
+        let mut failed = 0;
+        while let Some(results) = request.next().await {
+            for result in results? {
+                match result {
+                    ThumbResult::Ready { uri } => println!("ready\t{uri}"),
+                    ThumbResult::Failed { uri, message, .. } => {
+                        failed += 1;
+                        println!("failed\t{uri}\t{message}");
+                    }
+                }
+            }
+        }
+        match failed {
+            0 => Ok(()),
+            _ => Err(anyhow!("{failed} file(s) failed")),
+        }
+    })
+}
+
+fn url_from_path(path: &std::path::Path) -> anyhow::Result<String> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow!("not valid UTF-8: {path:?}"))?;
+    // Good enough for an example: only escapes what commonly shows up in names.
+    let escaped: String = path
+        .chars()
+        .map(|c| match c {
+            ' ' => "%20".to_owned(),
+            '%' => "%25".to_owned(),
+            '#' => "%23".to_owned(),
+            '?' => "%3F".to_owned(),
+            c => c.to_string(),
+        })
+        .collect();
+    Ok(format!("file://{escaped}"))
+}
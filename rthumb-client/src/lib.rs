@@ -0,0 +1,269 @@
+use std::time::Duration;
+
+use anyhow::{Context, anyhow};
+use futures_lite::{Stream, StreamExt, stream};
+use rthumbd::dbus::{ErrorCode, INTERFACE_PATH, MediaRef, Scheduler, ThumbFlavor, WELL_KNOWN_NAME};
+use zbus::{Connection, MatchRule, MessageStream, fdo, message};
+
+const INTERFACE: &str = "org.freedesktop.thumbnails.Thumbnailer1";
+
+#[zbus::proxy(
+    interface = "org.freedesktop.thumbnails.Thumbnailer1",
+    gen_blocking = false
+)]
+trait Thumbnailer1 {
+    fn queue(
+        &self,
+        uris: &[&str],
+        mime_types: &[&str],
+        flavor: &str,
+        scheduler: &str,
+        handle_to_unqueue: u32,
+    ) -> zbus::Result<u32>;
+
+    fn dequeue(&self, handle: u32) -> zbus::Result<()>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThumbResult {
+    Ready {
+        uri: String,
+    },
+    Failed {
+        uri: String,
+        // None for codes outside of the specification.
+        code: Option<ErrorCode>,
+        message: String,
+    },
+}
+
+#[derive(Clone)]
+pub struct Client {
+    connection: Connection,
+    proxy: Thumbnailer1Proxy<'static>,
+    name: String,
+    timeout: Option<Duration>,
+}
+
+impl Client {
+    pub async fn connect() -> anyhow::Result<Self> {
+        Self::with_connection(Connection::session().await?, WELL_KNOWN_NAME).await
+    }
+
+    pub async fn with_connection(connection: Connection, name: &str) -> anyhow::Result<Self> {
+        let proxy = Thumbnailer1Proxy::builder(&connection)
+            .destination(name.to_owned())?
+            .path(INTERFACE_PATH)?
+            .build()
+            .await?;
+        Ok(Self {
+            connection,
+            proxy,
+            name: name.to_owned(),
+            timeout: None,
+        })
+    }
+
+    // Requests fail once no signal arrived for them in that long.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub async fn queue(&self, medias: &[MediaRef], flavor: ThumbFlavor) -> anyhow::Result<Request> {
+        self.queue_with_scheduler(medias, flavor, Scheduler::Foreground)
+            .await
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(%flavor, %scheduler, n_uris = medias.len(), handle)
+        )
+    )]
+    pub async fn queue_with_scheduler(
+        &self,
+        medias: &[MediaRef],
+        flavor: ThumbFlavor,
+        scheduler: Scheduler,
+    ) -> anyhow::Result<Request> {
+        // Subscribe before queueing, or the first signals could be missed.
+        // Other thumbnailers may serve the interface at the same path, with
+        // handles of their own.
+        let rule = MatchRule::builder()
+            .msg_type(message::Type::Signal)
+            .sender(self.name.as_str())?
+            .interface(INTERFACE)?
+            .path(INTERFACE_PATH)?
+            .build();
+        let signals = MessageStream::for_match_rule(rule, &self.connection, None).await?;
+        let owner_changes = fdo::DBusProxy::new(&self.connection)
+            .await?
+            .receive_name_owner_changed_with_args(&[(0, self.name.as_str())])
+            .await?;
+
+        let uris: Vec<_> = medias.iter().map(|media| media.uri.as_str()).collect();
+        let mime_types: Vec<_> = medias
+            .iter()
+            .map(|media| media.mime_type.as_str())
+            .collect();
+        let handle = self
+            .proxy
+            .queue(
+                &uris,
+                &mime_types,
+                &flavor.to_string(),
+                &scheduler.to_string(),
+                0,
+            )
+            .await
+            .context("Queue call failed")?;
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("handle", handle);
+        Ok(Request {
+            handle,
+            proxy: self.proxy.clone(),
+            events: signals
+                .map(Event::Signal)
+                .or(owner_changes.filter_map(|signal| {
+                    // The name also changes owner when the daemon gets activated.
+                    let args = signal.args().ok()?;
+                    args.new_owner().is_none().then_some(Event::DaemonLeft)
+                }))
+                .boxed(),
+            timeout: self.timeout,
+            done: false,
+        })
+    }
+}
+
+enum Event {
+    Signal(zbus::Result<zbus::Message>),
+    DaemonLeft,
+}
+
+// Results of one Queue call, in the order the daemon reported them.
+pub struct Request {
+    handle: u32,
+    proxy: Thumbnailer1Proxy<'static>,
+    events: stream::Boxed<Event>,
+    timeout: Option<Duration>,
+    done: bool,
+}
+
+impl Request {
+    pub fn handle(&self) -> u32 {
+        self.handle
+    }
+
+    pub async fn dequeue(&self) -> anyhow::Result<()> {
+        Ok(self.proxy.dequeue(self.handle).await?)
+    }
+
+    // Returns None once the daemon finished the request. A Ready signal
+    // carries several URIs, hence results being returned in batches.
+    pub async fn next(&mut self) -> Option<anyhow::Result<Vec<ThumbResult>>> {
+        if self.done {
+            return None;
+        }
+        let result = self.next_event().await.transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = anyhow::Result<Vec<ThumbResult>>> {
+        stream::unfold(self, |mut request| async move {
+            request.next().await.map(|results| (results, request))
+        })
+    }
+
+    // Waits for the request to finish, returning all results.
+    pub async fn collect(mut self) -> anyhow::Result<Vec<ThumbResult>> {
+        let mut results = Vec::new();
+        while let Some(batch) = self.next().await {
+            results.extend(batch?);
+        }
+        Ok(results)
+    }
+
+    async fn next_event(&mut self) -> anyhow::Result<Option<Vec<ThumbResult>>> {
+        loop {
+            let event = match self.timeout {
+                Some(timeout) => {
+                    let timed_out = async {
+                        async_io::Timer::after(timeout).await;
+                        None
+                    };
+                    futures_lite::future::or(self.events.next(), timed_out)
+                        .await
+                        .ok_or_else(|| {
+                            anyhow!("no news of request {} for {timeout:?}", self.handle)
+                        })?
+                }
+                None => self
+                    .events
+                    .next()
+                    .await
+                    .ok_or_else(|| anyhow!("connection closed"))?,
+            };
+            let message = match event {
+                Event::Signal(message) => message?,
+                Event::DaemonLeft => {
+                    return Err(anyhow!(
+                        "thumbnailer left the bus before finishing request {}",
+                        self.handle
+                    ));
+                }
+            };
+            let header = message.header();
+            let Some(member) = header.member() else {
+                continue;
+            };
+            let body = message.body();
+            match member.as_str() {
+                "Ready" => {
+                    let (handle, uris): (u32, Vec<String>) = body.deserialize()?;
+                    if handle == self.handle {
+                        return Ok(Some(
+                            uris.into_iter()
+                                .map(|uri| ThumbResult::Ready { uri })
+                                .collect(),
+                        ));
+                    }
+                }
+                "Error" => {
+                    // The specification sends a list of URIs, rthumbd a single one.
+                    let (handle, uris, code, message) = body
+                        .deserialize::<(u32, Vec<String>, i32, String)>()
+                        .or_else(|_| {
+                            body.deserialize::<(u32, String, i32, String)>().map(
+                                |(handle, uri, code, message)| (handle, vec![uri], code, message),
+                            )
+                        })?;
+                    if handle == self.handle {
+                        return Ok(Some(
+                            uris.into_iter()
+                                .map(|uri| ThumbResult::Failed {
+                                    uri,
+                                    code: ErrorCode::try_from(code).ok(),
+                                    message: message.clone(),
+                                })
+                                .collect(),
+                        ));
+                    }
+                }
+                "Finished" => {
+                    let handle: u32 = body.deserialize()?;
+                    if handle == self.handle {
+                        return Ok(None);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use std::{
+    fmt,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use itertools::Itertools;
+use log::{debug, info, warn};
+
+use crate::{
+    dbus::{FlavorSet, ThumbFlavor},
+    xdg::{
+        FAIL_APP_NAME, ThumbFsMeta, create_private_dir_all, destination_filename, fail_dir,
+        get_thumb_full_metadata, get_thumb_original_metadata, temp_filename,
+    },
+};
+
+// Touched after each cleanup, so that the daemon keeps to its interval across
+// restarts.
+const STAMP_FILENAME: &str = ".rthumb-cleanup";
+
+// Temporary files younger than this may still be written to, by this or
+// another instance.
+const TEMP_FILE_GRACE: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Default)]
+pub struct CleanupOptions {
+    // Thumbnails last written or used longer ago than this are removed, even
+    // if their original is still around.
+    pub max_age: Option<Duration>,
+    // Leaves alone what other thumbnailers wrote, including unreadable files.
+    pub only_ours: bool,
+    pub flavors: FlavorSet,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CleanupStats {
+    pub removed: u64,
+    pub reclaimed_bytes: u64,
+}
+
+impl fmt::Display for CleanupStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "removed {} file(s), reclaimed {} bytes",
+            self.removed, self.reclaimed_bytes
+        )
+    }
+}
+
+// Removes thumbnails and failure markers whose original is gone, unreadable
+// thumbnails, and optionally old ones. PNGs without a Thumb::URI are not ours and are left
+// alone.
+pub fn cleanup(cache_dir: &Path, options: &CleanupOptions) -> anyhow::Result<CleanupStats> {
+    let mut stats = CleanupStats::default();
+    let now = SystemTime::now();
+    let dirs = options
+        .flavors
+        .iter()
+        .map(|flavor| flavor.cache_path(cache_dir))
+        .chain([fail_dir(cache_dir, FAIL_APP_NAME)]);
+    for dir in dirs {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "png") {
+                continue;
+            }
+            let Ok(file_meta) = entry.metadata() else {
+                continue;
+            };
+            if !file_meta.is_file() {
+                continue;
+            }
+            let reason = match get_thumb_full_metadata(&path) {
+                Ok(meta) if options.only_ours && !meta.is_ours() => continue,
+                Ok(meta) => {
+                    let age = file_meta
+                        .modified()
+                        .ok()
+                        .and_then(|modified| now.duration_since(modified).ok());
+                    if original_is_gone(&meta.fs.uri) {
+                        "original is gone"
+                    } else if options
+                        .max_age
+                        .is_some_and(|max_age| age.is_some_and(|age| age > max_age))
+                    {
+                        "too old"
+                    } else {
+                        continue;
+                    }
+                }
+                Err(err)
+                    if !options.only_ours
+                        && (err.downcast_ref::<png::DecodingError>().is_some()
+                            || err.downcast_ref::<std::io::Error>().is_some()) =>
+                {
+                    "unreadable"
+                }
+                Err(_) => continue,
+            };
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    debug!("removed {path:?}: {reason}");
+                    stats.removed += 1;
+                    stats.reclaimed_bytes += file_meta.len();
+                }
+                Err(err) => warn!("cannot remove {path:?}: {err}"),
+            }
+        }
+    }
+    std::fs::File::create(cache_dir.join(STAMP_FILENAME))?;
+    Ok(stats)
+}
+
+// None if no cleanup ever completed.
+pub fn since_last_cleanup(cache_dir: &Path) -> Option<Duration> {
+    let modified = std::fs::metadata(cache_dir.join(STAMP_FILENAME))
+        .and_then(|meta| meta.modified())
+        .ok()?;
+    Some(
+        SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default(),
+    )
+}
+
+// Only file:// originals can be checked.
+fn original_is_gone(uri: &str) -> bool {
+    let Some(path) = url::Url::parse(uri)
+        .ok()
+        .and_then(|uri| uri.to_file_path().ok())
+    else {
+        return false;
+    };
+    matches!(std::fs::metadata(path), Err(err) if err.kind() == std::io::ErrorKind::NotFound)
+}
+
+// Marks a thumbnail as recently used, for eviction to keep it.
+pub fn touch(path: &Path) -> std::io::Result<()> {
+    std::fs::File::open(path)?.set_modified(SystemTime::now())
+}
+
+// Removes the least recently used thumbnails until the cache fits in
+// `max_bytes`. Thumbnails used or written after `since` are kept, so that
+// requests running meanwhile never lose theirs.
+pub fn evict(
+    cache_dir: &Path,
+    flavors: &FlavorSet,
+    max_bytes: u64,
+    since: SystemTime,
+) -> anyhow::Result<CleanupStats> {
+    let mut stats = CleanupStats::default();
+    let mut thumbnails = Vec::new();
+    let mut total = 0;
+    for flavor in flavors.iter() {
+        let entries = match std::fs::read_dir(flavor.cache_path(cache_dir)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "png") {
+                continue;
+            }
+            // Other writers may remove files while this runs.
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let Ok(modified) = meta.modified() else {
+                continue;
+            };
+            if meta.is_file() {
+                total += meta.len();
+                thumbnails.push((modified, meta.len(), path));
+            }
+        }
+    }
+    if total <= max_bytes {
+        return Ok(stats);
+    }
+    thumbnails.sort_unstable();
+    for (modified, len, path) in thumbnails {
+        if total <= max_bytes || modified >= since {
+            break;
+        }
+        // It may have been used since it was listed.
+        if std::fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .is_ok_and(|modified| modified >= since)
+        {
+            continue;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                debug!("evicted {path:?}");
+                stats.removed += 1;
+                stats.reclaimed_bytes += len;
+                total -= len;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => total -= len,
+            Err(err) => warn!("cannot remove {path:?}: {err}"),
+        }
+    }
+    if total > max_bytes {
+        warn!("cache is still {total} bytes large, over the {max_bytes} bytes quota");
+    }
+    Ok(stats)
+}
+
+// Whether this is named like xdg::temp_filename() names files.
+fn is_temp_filename(name: &str) -> bool {
+    name.split_once(".tmp").is_some_and(|(hash, id)| {
+        hash.len() == 32
+            && hash.bytes().all(|b| b.is_ascii_hexdigit())
+            && !id.is_empty()
+            && id.bytes().all(|b| b.is_ascii_digit())
+    })
+}
+
+// Removes temporary files left over by interrupted thumbnail writes.
+pub fn cleanup_temp_files(cache_dir: &Path, flavors: &FlavorSet) -> anyhow::Result<CleanupStats> {
+    let mut stats = CleanupStats::default();
+    let now = SystemTime::now();
+    for flavor in flavors.iter() {
+        let entries = match std::fs::read_dir(flavor.cache_path(cache_dir)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_name().to_str().is_some_and(is_temp_filename) {
+                continue;
+            }
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let fresh = meta.modified().is_ok_and(|modified| {
+                now.duration_since(modified).unwrap_or_default() < TEMP_FILE_GRACE
+            });
+            if fresh || !meta.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    debug!("removed leftover {path:?}");
+                    stats.removed += 1;
+                    stats.reclaimed_bytes += meta.len();
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => warn!("cannot remove {path:?}: {err}"),
+            }
+        }
+    }
+    Ok(stats)
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+// Brings an up-to-date thumbnail of `original` from a flavor directory of the
+// legacy cache into the same one of the cache, returning its new path. Hard
+// links when possible.
+pub fn adopt_legacy_thumbnail(
+    legacy_dir: &Path,
+    dir: &Path,
+    original: &ThumbFsMeta,
+    id: usize,
+) -> anyhow::Result<Option<PathBuf>> {
+    let legacy_path = destination_filename(legacy_dir, &original.uri);
+    match get_thumb_original_metadata(&legacy_path) {
+        Ok(meta) if meta == *original => {}
+        _ => return Ok(None),
+    }
+    let thumb_path = destination_filename(dir, &original.uri);
+    // The legacy directory may be a link to the cache itself.
+    if same_file(&legacy_path, &thumb_path) {
+        return Ok(None);
+    }
+    let temp_path = temp_filename(dir, &original.uri, id);
+    let adopted = std::fs::hard_link(&legacy_path, &temp_path)
+        .or_else(|_| {
+            std::fs::copy(&legacy_path, &temp_path)?;
+            std::fs::set_permissions(&temp_path, std::fs::Permissions::from_mode(0o600))
+        })
+        .and_then(|()| std::fs::rename(&temp_path, &thumb_path));
+    if let Err(err) = adopted {
+        _ = std::fs::remove_file(&temp_path);
+        return Err(err.into());
+    }
+    debug!("adopted {legacy_path:?}");
+    Ok(Some(thumb_path))
+}
+
+// Moves everything from the legacy cache into `cache_dir` and leaves a
+// symbolic link behind, so that applications still using the legacy location
+// share the cache. Does nothing if there is no legacy cache or it already is
+// a link. Thumbnails present in both places are kept from `cache_dir`.
+// Returns how many files were moved.
+pub fn migrate_legacy_cache(legacy_dir: &Path, cache_dir: &Path) -> anyhow::Result<u64> {
+    let mut moved = 0;
+    match std::fs::symlink_metadata(legacy_dir) {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => return Ok(moved),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(moved),
+        Err(err) => return Err(err.into()),
+    }
+    if same_file(legacy_dir, cache_dir) {
+        return Ok(moved);
+    }
+    create_private_dir_all(cache_dir)?;
+    move_tree(legacy_dir, cache_dir, &mut moved)?;
+    std::fs::remove_dir(legacy_dir)?;
+    std::os::unix::fs::symlink(cache_dir, legacy_dir)?;
+    Ok(moved)
+}
+
+// Moves the content of `from` into `to`, then removes `from`'s content.
+fn move_tree(from: &Path, to: &Path, moved: &mut u64) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let source = entry.path();
+        let destination = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            create_private_dir_all(&destination)?;
+            move_tree(&source, &destination, moved)?;
+            std::fs::remove_dir(&source)?;
+            continue;
+        }
+        if !std::fs::exists(&destination)? {
+            if let Err(err) = std::fs::rename(&source, &destination) {
+                // Across file systems.
+                if err.kind() != std::io::ErrorKind::CrossesDevices {
+                    return Err(err.into());
+                }
+                std::fs::copy(&source, &destination)?;
+            }
+            *moved += 1;
+        }
+        if std::fs::exists(&source)? {
+            std::fs::remove_file(&source)?;
+        }
+    }
+    Ok(())
+}
+
+// Warns about cache directories others can access, or restricts them to their
+// owner if `fix` is set.
+pub fn check_permissions(cache_dir: &Path, flavors: &FlavorSet, fix: bool) -> anyhow::Result<()> {
+    let dirs = [cache_dir.to_owned()]
+        .into_iter()
+        .chain(flavors.iter().map(|flavor| flavor.cache_path(cache_dir)))
+        .chain([fail_dir(cache_dir, FAIL_APP_NAME)]);
+    for dir in dirs {
+        let mode = match std::fs::metadata(&dir) {
+            Ok(meta) => meta.permissions().mode(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if mode & 0o077 == 0 {
+            continue;
+        }
+        if fix {
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(mode & !0o077))?;
+            info!("restricted {dir:?} to its owner");
+        } else {
+            warn!(
+                "{dir:?} is accessible to other users (mode {:o})",
+                mode & 0o777
+            );
+        }
+    }
+    Ok(())
+}
+
+// Path of an up-to-date thumbnail of the file at `uri` in `flavor`, if any.
+// Only fails if the original cannot be examined.
+pub fn find_thumbnail(
+    cache_dir: &Path,
+    uri: &str,
+    flavor: &ThumbFlavor,
+) -> anyhow::Result<Option<PathBuf>> {
+    let original = original_metadata(uri)?;
+    Ok(find_fresh(cache_dir, &original, flavor))
+}
+
+// Like find_thumbnail(), but in the smallest flavor at least `size` pixels
+// large that has one, for callers happy to scale it down.
+pub fn find_thumbnail_at_least(
+    cache_dir: &Path,
+    flavors: &FlavorSet,
+    uri: &str,
+    size: u32,
+) -> anyhow::Result<Option<PathBuf>> {
+    let original = original_metadata(uri)?;
+    Ok(flavors
+        .iter()
+        .filter(|flavor| flavor.dimension() >= size)
+        .sorted_by_key(|flavor| flavor.dimension())
+        .find_map(|flavor| find_fresh(cache_dir, &original, flavor)))
+}
+
+fn original_metadata(uri: &str) -> anyhow::Result<ThumbFsMeta> {
+    let path = url::Url::parse(uri)?
+        .to_file_path()
+        .map_err(|()| anyhow::anyhow!("not a file:// URI: {uri}"))?;
+    ThumbFsMeta::from(uri, &path)
+}
+
+fn find_fresh(cache_dir: &Path, original: &ThumbFsMeta, flavor: &ThumbFlavor) -> Option<PathBuf> {
+    let path = destination_filename(&flavor.cache_path(cache_dir), &original.uri);
+    get_thumb_original_metadata(&path)
+        .is_ok_and(|meta| meta == *original)
+        .then_some(path)
+}
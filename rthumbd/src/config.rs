@@ -0,0 +1,278 @@
+use std::{
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use log::warn;
+use rthumbd::{
+    cache::CleanupOptions,
+    dbus::{self, BusType, FlavorSet, ThumbFlavor},
+    exec::{self, ExecMode, ExecThumbnailers},
+    sandbox::SandboxMode,
+    xdg::{PngCompression, ThumbWriteOptions, config_home},
+};
+use serde::{Deserialize, Serialize};
+
+// Effective configuration: defaults, overridden by the configuration file,
+// overridden by environment variables and command line flags.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    pub bus: BusType,
+    pub bus_name: String,
+    // Defaults to $XDG_CACHE_HOME/thumbnails.
+    pub cache_dir: Option<PathBuf>,
+    // Defaults to the number of CPUs.
+    pub threads: Option<NonZeroUsize>,
+    // Threads of the pool of their own background requests get, defaults to
+    // half the number of threads.
+    pub background_threads: Option<NonZeroUsize>,
+    // Added to the niceness of those threads, which also only get the I/O
+    // time nothing else wants unless 0.
+    pub background_niceness: i32,
+    pub chunk_size: NonZeroUsize,
+    // Defaults to the number of CPUs divided by the chunk size.
+    pub max_requests: Option<NonZeroUsize>,
+    // URIs a single Queue call may carry, 0 for no limit.
+    pub max_queue_uris: usize,
+    // Split larger Queue calls into several jobs rather than refusing them.
+    pub split_large_queues: bool,
+    pub mime_sniffing: bool,
+    pub drain_timeout: u64,
+    pub idle_timeout: u64,
+    // Seconds between cache cleanups while idle, 0 to never clean up.
+    pub cleanup_interval: u64,
+    // Days after which thumbnails are removed by cleanups, even if their
+    // original is still around.
+    pub cleanup_max_age: Option<u64>,
+    // Megabytes the cache may take up before the least recently used
+    // thumbnails get evicted, 0 for no limit.
+    pub cache_quota: u64,
+    // Reuse up-to-date thumbnails from ~/.thumbnails rather than rendering
+    // them again.
+    pub legacy_cache_fallback: bool,
+    // Move ~/.thumbnails into the cache on startup, leaving a link behind.
+    pub migrate_legacy_cache: bool,
+    // Use .sh_thumbnails directories next to files on other file systems.
+    pub shared_repositories: bool,
+    // Restrict cache directories to their owner on startup, rather than only
+    // warning about them.
+    pub fix_permissions: bool,
+    // Also compare the original's inode and ctime to the thumbnail's.
+    pub strict_validation: bool,
+    // Seconds after which to give up on a single media, 0 to wait forever.
+    pub media_timeout: u64,
+    // Seconds during which to fail medias right away once the cache's file
+    // system is found full or read-only, 0 to always try.
+    pub cache_full_cooldown: u64,
+    // Megabytes above which originals are not thumbnailed, 0 for no limit.
+    pub max_source_size: u64,
+    // Number of up-to-date thumbnails to remember, to check them again
+    // without reading them.
+    pub validation_cache_size: usize,
+    pub png_compression: PngCompression,
+    // Smaller but slower to write thumbnails.
+    pub png_adaptive_filter: bool,
+    // Flush thumbnails to disk as they are written, for flaky power.
+    pub durable_writes: bool,
+    // Pixels across up to which thumbnails may be written with a palette,
+    // which makes them several times smaller; 0 to never.
+    pub palette_max_size: u32,
+    // Where to serve Prometheus metrics, if built with the metrics feature.
+    pub metrics_address: Option<SocketAddr>,
+    // Log hashes instead of file names.
+    pub redact_uris: bool,
+    // Whether to run the .thumbnailer files of other programs.
+    pub exec_thumbnailers: ExecMode,
+    // Seconds after which to kill them.
+    pub exec_timeout: u64,
+    // Where to look for .thumbnailer files, defaults to the thumbnailers
+    // directories of $XDG_DATA_HOME and $XDG_DATA_DIRS.
+    pub thumbnailer_dirs: Vec<PathBuf>,
+    // Directories of recently thumbnailed files to watch, if built with the
+    // watch feature, removing the thumbnails of files that change; 0 to not
+    // watch any.
+    pub watch_dirs: usize,
+    // Decode in subprocesses, one per thread, so that a crashing decoder
+    // only fails the media it was reading.
+    pub isolate_decoders: bool,
+    // How much to restrict the daemon once it is set up.
+    pub sandbox: SandboxMode,
+    // Flavors clients may not ask for, e.g. to never write large thumbnails.
+    pub disabled_flavors: Vec<String>,
+    // Flavors clients may ask for, replacing those of the specification.
+    pub flavors: Vec<FlavorConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FlavorConfig {
+    pub name: String,
+    // Pixels across.
+    pub dimension: u32,
+    // Directory of the flavor's thumbnails in the cache, defaults to its name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bus: BusType::Session,
+            bus_name: dbus::WELL_KNOWN_NAME.to_owned(),
+            cache_dir: None,
+            threads: None,
+            background_threads: None,
+            background_niceness: 10,
+            chunk_size: NonZeroUsize::new(2).unwrap(),
+            max_requests: None,
+            max_queue_uris: 10_000,
+            split_large_queues: false,
+            mime_sniffing: false,
+            drain_timeout: 10,
+            idle_timeout: 300,
+            cleanup_interval: 0,
+            cleanup_max_age: None,
+            cache_quota: 0,
+            legacy_cache_fallback: false,
+            migrate_legacy_cache: false,
+            shared_repositories: false,
+            fix_permissions: false,
+            strict_validation: false,
+            media_timeout: 0,
+            cache_full_cooldown: 60,
+            max_source_size: 0,
+            validation_cache_size: 4096,
+            png_compression: PngCompression::Fast,
+            png_adaptive_filter: false,
+            durable_writes: false,
+            palette_max_size: 0,
+            metrics_address: None,
+            redact_uris: false,
+            exec_thumbnailers: ExecMode::Off,
+            exec_timeout: 30,
+            thumbnailer_dirs: Vec::new(),
+            watch_dirs: 0,
+            isolate_decoders: false,
+            sandbox: SandboxMode::Off,
+            disabled_flavors: Vec::new(),
+            flavors: ThumbFlavor::all()
+                .map(|flavor| FlavorConfig {
+                    name: flavor.name().to_owned(),
+                    dimension: flavor.dimension(),
+                    directory: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+pub fn default_path() -> anyhow::Result<PathBuf> {
+    Ok(config_home()?.join("rthumb").join("config.toml"))
+}
+
+impl Config {
+    // A missing file is only an error if it was asked for explicitly.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => (default_path()?, false),
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if !explicit && err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(err) => return Err(err).context(format!("cannot read {}", path.display())),
+        };
+        Self::parse(&text).with_context(|| format!("invalid configuration in {}", path.display()))
+    }
+
+    // Unknown keys are ignored, so that older versions accept newer files.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        Ok(serde_ignored::deserialize(
+            toml::Deserializer::new(text),
+            |key| warn!("ignoring unknown configuration key '{key}'"),
+        )?)
+    }
+
+    pub fn cleanup_options(&self, flavors: &FlavorSet) -> CleanupOptions {
+        CleanupOptions {
+            max_age: self
+                .cleanup_max_age
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            flavors: flavors.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn flavor_set(&self) -> anyhow::Result<FlavorSet> {
+        let is_known = |name: &String| self.flavors.iter().any(|flavor| flavor.name == *name);
+        if let Some(name) = self.disabled_flavors.iter().find(|name| !is_known(name)) {
+            bail!("cannot disable unknown flavor '{name}'");
+        }
+        let flavors = self
+            .flavors
+            .iter()
+            .filter(|flavor| !self.disabled_flavors.contains(&flavor.name))
+            .map(|flavor| {
+                let dir = flavor.directory.as_deref().unwrap_or(&flavor.name);
+                ThumbFlavor::new(&flavor.name, dir, flavor.dimension)
+            })
+            .collect::<anyhow::Result<Vec<_>>>();
+        flavors.and_then(FlavorSet::new).context("invalid flavors")
+    }
+
+    // None if unlimited.
+    pub fn cache_quota_bytes(&self) -> Option<u64> {
+        (self.cache_quota > 0).then(|| self.cache_quota.saturating_mul(1_000_000))
+    }
+
+    pub fn write_options(&self) -> ThumbWriteOptions {
+        ThumbWriteOptions {
+            compression: self.png_compression,
+            adaptive_filter: self.png_adaptive_filter,
+            durable: self.durable_writes,
+            palette_max_size: self.palette_max_size,
+        }
+    }
+
+    // None if unlimited.
+    pub fn max_queue_uris(&self) -> Option<usize> {
+        (self.max_queue_uris > 0).then_some(self.max_queue_uris)
+    }
+
+    // None if unlimited.
+    pub fn max_source_bytes(&self) -> Option<u64> {
+        (self.max_source_size > 0).then(|| self.max_source_size.saturating_mul(1_000_000))
+    }
+
+    // None if unlimited.
+    pub fn media_timeout(&self) -> Option<Duration> {
+        (self.media_timeout > 0).then(|| Duration::from_secs(self.media_timeout))
+    }
+
+    pub fn cache_full_cooldown(&self) -> Duration {
+        Duration::from_secs(self.cache_full_cooldown)
+    }
+
+    pub fn exec_thumbnailers(&self) -> ExecThumbnailers {
+        let dirs = if self.thumbnailer_dirs.is_empty() {
+            exec::default_dirs()
+        } else {
+            self.thumbnailer_dirs.clone()
+        };
+        ExecThumbnailers::load(
+            &dirs,
+            self.exec_thumbnailers,
+            Duration::from_secs(self.exec_timeout),
+        )
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
@@ -1,26 +1,432 @@
 use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
     fmt,
-    path::{Path, PathBuf},
-    sync::atomic,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, atomic},
+    time::{Duration, Instant},
 };
 
+use anyhow::{anyhow, bail};
+use futures_lite::StreamExt;
 use itertools::Itertools;
-use tokio::sync::mpsc;
+use log::{debug, info, warn};
+use tokio::sync::{mpsc, oneshot};
 use zbus::{
-    fdo,
-    object_server::SignalEmitter,
+    MatchRule, MessageStream,
+    fdo::{self, RequestNameFlags, RequestNameReply},
+    message::Header,
+    names::{BusName, UniqueName},
+    object_server::{ResponseDispatchNotifier, SignalEmitter},
+    proxy::CacheProperties,
     zvariant::{self},
 };
 
+use crate::thumbnail::{SUPPORTED_SCHEMES, handles_mime_type, supported_mime_types};
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MediaRef {
     pub uri: String,
     pub mime_type: String,
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<atomic::AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(atomic::Ordering::Relaxed)
+    }
+}
+
+// Who queued a request, for local logs only: it is never sent over the bus.
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    // Unique bus name, empty if the message had no sender.
+    pub name: String,
+    pub pid: Option<u32>,
+}
+
+impl Client {
+    // Best effort: the PID is left out if the bus cannot tell it.
+    async fn lookup(connection: &zbus::Connection, name: Option<&UniqueName<'_>>) -> Self {
+        let Some(name) = name else {
+            return Self::default();
+        };
+        let proxy = fdo::DBusProxy::builder(connection)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await;
+        let pid = match proxy {
+            Ok(proxy) => proxy
+                .get_connection_unix_process_id(name.as_ref().into())
+                .await
+                .ok(),
+            Err(_) => None,
+        };
+        Self {
+            name: name.to_string(),
+            pid,
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = if self.name.is_empty() {
+            "unknown"
+        } else {
+            &self.name
+        };
+        match self.pid {
+            Some(pid) => write!(f, "{name} (pid {pid})"),
+            None => f.write_str(name),
+        }
+    }
+}
+
+struct LiveHandle {
+    cancel: CancelToken,
+    client: Client,
+    flavor: ThumbFlavor,
+    scheduler: Scheduler,
+    uris: usize,
+    // URIs reported ready or failed so far.
+    done: usize,
+    // Jobs of the daemon itself and parts handed to specialized thumbnailers
+    // that are not done yet; Finished is only emitted once both are none.
+    jobs: usize,
+    delegations: usize,
+    started: bool,
+    // Set when the client that queued the handle left the bus: nobody is
+    // listening for its signals anymore.
+    abandoned: bool,
+}
+
+// Handles that were queued but whose Finished signal has not been emitted yet.
+#[derive(Clone, Default)]
+pub struct LiveHandles(Arc<Mutex<HashMap<u32, LiveHandle>>>);
+
+impl LiveHandles {
+    // `job` is the first of `jobs` queued for the handle, with `uris` medias
+    // between them.
+    fn insert(&self, handle: u32, job: &ThumbJob, uris: usize, jobs: usize) {
+        self.0.lock().unwrap().insert(
+            handle,
+            LiveHandle {
+                cancel: job.cancel.clone(),
+                client: job.client.clone(),
+                flavor: job.flavor.clone(),
+                scheduler: job.scheduler,
+                uris,
+                done: 0,
+                jobs,
+                delegations: 0,
+                started: false,
+                abandoned: false,
+            },
+        );
+    }
+
+    fn delegate(&self, handle: u32, uris: usize) {
+        if let Some(live) = self.0.lock().unwrap().get_mut(&handle) {
+            live.uris += uris;
+            live.delegations += 1;
+        }
+    }
+
+    // True the first time, when Started is to be emitted.
+    fn start(&self, handle: u32) -> bool {
+        match self.0.lock().unwrap().get_mut(&handle) {
+            Some(live) => !std::mem::replace(&mut live.started, true),
+            None => false,
+        }
+    }
+
+    // Both true once the handle is done, when Finished is to be emitted.
+    fn finish(&self, handle: u32) -> bool {
+        self.update_done(handle, |live| live.jobs -= 1)
+    }
+
+    fn delegation_done(&self, handle: u32) -> bool {
+        self.update_done(handle, |live| live.delegations -= 1)
+    }
+
+    fn update_done(&self, handle: u32, update: impl FnOnce(&mut LiveHandle)) -> bool {
+        let mut handles = self.0.lock().unwrap();
+        let Some(live) = handles.get_mut(&handle) else {
+            return false;
+        };
+        update(live);
+        let done = live.jobs == 0 && live.delegations == 0;
+        if done {
+            handles.remove(&handle);
+        }
+        done
+    }
+
+    fn progress(&self, handle: u32, uris: usize) {
+        if let Some(live) = self.0.lock().unwrap().get_mut(&handle) {
+            live.done += uris;
+        }
+    }
+
+    fn cancel(&self, handle: u32) -> bool {
+        match self.0.lock().unwrap().get(&handle) {
+            Some(live) => {
+                live.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn abandon_sender(&self, sender: &str) -> usize {
+        let mut handles = self.0.lock().unwrap();
+        let mut count = 0;
+        for live in handles
+            .values_mut()
+            .filter(|live| live.client.name == sender)
+        {
+            live.cancel.cancel();
+            live.abandoned = true;
+            count += 1;
+        }
+        count
+    }
+
+    fn is_abandoned(&self, handle: u32) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&handle)
+            .is_some_and(|live| live.abandoned)
+    }
+
+    fn remove(&self, handle: u32) {
+        self.0.lock().unwrap().remove(&handle);
+    }
+
+    // Includes handles whose jobs are all done but whose parts handed to
+    // specialized thumbnailers are still being relayed.
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    fn log(&self) {
+        let handles = self.0.lock().unwrap();
+        info!("{} live request(s)", handles.len());
+        for (handle, live) in handles.iter().sorted_by_key(|(handle, _)| **handle) {
+            info!(
+                "request {handle} from {}: {} of {} URI(s) done{}",
+                live.client,
+                live.done,
+                live.uris,
+                if live.abandoned { ", abandoned" } else { "" }
+            );
+        }
+    }
+
+    // In handle order. Counts only, never URIs, as anyone on the bus may ask.
+    fn queue(&self) -> Vec<(u32, String, String, u32, u32)> {
+        let handles = self.0.lock().unwrap();
+        handles
+            .iter()
+            .sorted_by_key(|(handle, _)| **handle)
+            .map(|(handle, live)| {
+                (
+                    *handle,
+                    live.scheduler.to_string(),
+                    live.flavor.name().to_owned(),
+                    u32::try_from(live.uris).unwrap_or(u32::MAX),
+                    u32::try_from(live.done).unwrap_or(u32::MAX),
+                )
+            })
+            .collect()
+    }
+}
+
+const SPECIALIZED_INTERFACE: &str = "org.freedesktop.thumbnails.SpecializedThumbnailer1";
+// How long a specialized thumbnailer may stay silent before the medias handed
+// to it are reported as failed.
+const DELEGATION_TIMEOUT: Duration = Duration::from_secs(60);
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Medias of a request handed to a specialized thumbnailer, whose signals are
+// relayed as if the daemon emitted them for the request itself.
+struct Delegation {
+    connection: zbus::Connection,
+    owner: String,
+    path: String,
+    handle: u32,
+    flavor: String,
+    scheduler: String,
+    cancel: CancelToken,
+    reply_tx: mpsc::WeakSender<Reply>,
+}
+
+impl Delegation {
+    async fn run(self, medias: Vec<MediaRef>) {
+        let mut pending: HashSet<_> = medias.iter().map(|media| media.uri.clone()).collect();
+        if let Err(err) = self.relay(&medias, &mut pending).await {
+            warn!(
+                handle = self.handle;
+                "specialized thumbnailer {}{} failed: {err:#}",
+                self.owner,
+                self.path
+            );
+            for uri in pending {
+                self.reply(Reply::Error {
+                    handle: self.handle,
+                    uri,
+                    code: ErrorCode::Failed,
+                    message: format!("specialized thumbnailer failed: {err:#}"),
+                })
+                .await;
+            }
+        }
+        self.reply(Reply::Delegated {
+            handle: self.handle,
+        })
+        .await;
+    }
+
+    // Not holding on to the sender, so that the daemon can stop meanwhile.
+    async fn reply(&self, reply: Reply) {
+        if let Some(reply_tx) = self.reply_tx.upgrade() {
+            _ = reply_tx.send(reply).await;
+        }
+    }
+
+    // Returns once the thumbnailer finished, or the request was cancelled.
+    // URIs still in `pending` on error were not reported.
+    async fn relay(
+        &self,
+        medias: &[MediaRef],
+        pending: &mut HashSet<String>,
+    ) -> anyhow::Result<()> {
+        // Listening before queuing, so that no signal is missed.
+        let signals = MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .sender(self.owner.as_str())?
+            .path(self.path.as_str())?
+            .interface(SPECIALIZED_INTERFACE)?
+            .build();
+        let owner_changes = MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .sender("org.freedesktop.DBus")?
+            .interface("org.freedesktop.DBus")?
+            .member("NameOwnerChanged")?
+            .arg(0, self.owner.as_str())?
+            .build();
+        let mut messages = futures_lite::stream::or(
+            MessageStream::for_match_rule(signals, &self.connection, None).await?,
+            MessageStream::for_match_rule(owner_changes, &self.connection, None).await?,
+        );
+        let (uris, mime_types): (Vec<_>, Vec<_>) = medias
+            .iter()
+            .map(|media| (media.uri.as_str(), media.mime_type.as_str()))
+            .unzip();
+        let sub_handle: u32 = self
+            .call(
+                "Queue",
+                &(uris, mime_types, &self.flavor, &self.scheduler, 0u32),
+            )
+            .await?
+            .body()
+            .deserialize()?;
+        let mut last_activity = Instant::now();
+        loop {
+            if self.cancel.is_cancelled() {
+                _ = self.call("Dequeue", &sub_handle).await;
+                pending.clear();
+                return Ok(());
+            }
+            let message = match tokio::time::timeout(CANCEL_POLL_INTERVAL, messages.next()).await {
+                Ok(Some(message)) => message?,
+                Ok(None) => bail!("lost the D-Bus connection"),
+                Err(_) if last_activity.elapsed() > DELEGATION_TIMEOUT => {
+                    _ = self.call("Dequeue", &sub_handle).await;
+                    bail!("no news for {DELEGATION_TIMEOUT:?}");
+                }
+                Err(_) => continue,
+            };
+            last_activity = Instant::now();
+            let header = message.header();
+            let body = message.body();
+            match header.member().map(|member| member.as_str()) {
+                Some("NameOwnerChanged") => bail!("it left the bus"),
+                Some("Ready") => {
+                    let (handle, mut uris): (u32, Vec<String>) = body.deserialize()?;
+                    uris.retain(|uri| handle == sub_handle && pending.remove(uri));
+                    if !uris.is_empty() {
+                        self.reply(Reply::Ready {
+                            handle: self.handle,
+                            uris,
+                            paths: Vec::new(),
+                        })
+                        .await;
+                    }
+                }
+                Some("Error") => {
+                    let (handle, uris, code, message): (u32, Vec<String>, i32, String) =
+                        body.deserialize()?;
+                    for uri in uris {
+                        if handle == sub_handle && pending.remove(&uri) {
+                            self.reply(Reply::Error {
+                                handle: self.handle,
+                                uri,
+                                code: ErrorCode::try_from(code).unwrap_or(ErrorCode::Failed),
+                                message: message.clone(),
+                            })
+                            .await;
+                        }
+                    }
+                }
+                Some("Finished") if body.deserialize::<u32>()? == sub_handle => {
+                    if !pending.is_empty() {
+                        bail!("finished without reporting {} URI(s)", pending.len());
+                    }
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn call(
+        &self,
+        method: &str,
+        body: &(impl serde::Serialize + zvariant::DynamicType),
+    ) -> zbus::Result<zbus::Message> {
+        self.connection
+            .call_method(
+                Some(self.owner.as_str()),
+                self.path.as_str(),
+                Some(SPECIALIZED_INTERFACE),
+                method,
+                body,
+            )
+            .await
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ThumbJob {
     pub handle: u32,
     pub flavor: ThumbFlavor,
+    pub scheduler: Scheduler,
     pub medias: Vec<MediaRef>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub cancel: CancelToken,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub client: Client,
 }
 
 impl fmt::Debug for ThumbJob {
@@ -28,82 +434,240 @@ impl fmt::Debug for ThumbJob {
         f.debug_struct("ThumbJob")
             .field("handle", &self.handle)
             .field("flavor", &self.flavor)
+            .field("scheduler", &self.scheduler)
             .field("medias (len)", &self.medias.len())
+            .field("cancelled", &self.cancel.is_cancelled())
+            .field("client", &format_args!("{}", self.client))
             .finish()
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum ThumbFlavor {
-    Normal,
-    Large,
-    XLarge,
-    XXLarge,
+// A size of thumbnails, stored in a directory of its own in the cache. The
+// specification defines four of them, but daemons may serve others.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ThumbFlavor {
+    name: Cow<'static, str>,
+    dir: Cow<'static, str>,
+    dimension: u32,
 }
 
 impl ThumbFlavor {
+    pub const NORMAL: ThumbFlavor = ThumbFlavor::spec("normal", 128);
+    pub const LARGE: ThumbFlavor = ThumbFlavor::spec("large", 256);
+    pub const X_LARGE: ThumbFlavor = ThumbFlavor::spec("x-large", 512);
+    pub const XX_LARGE: ThumbFlavor = ThumbFlavor::spec("xx-large", 1024);
+
+    const fn spec(name: &'static str, dimension: u32) -> Self {
+        Self {
+            name: Cow::Borrowed(name),
+            dir: Cow::Borrowed(name),
+            dimension,
+        }
+    }
+
+    // `dir` is the name of the flavor's directory in the cache.
+    pub fn new(name: &str, dir: &str, dimension: u32) -> anyhow::Result<Self> {
+        if name.is_empty() {
+            bail!("flavor names cannot be empty");
+        }
+        if dimension == 0 {
+            bail!("flavor '{name}' must be at least 1 pixel large");
+        }
+        if !matches!(
+            Path::new(dir).components().collect_vec().as_slice(),
+            [Component::Normal(_)]
+        ) {
+            bail!("flavor '{name}' has an invalid directory name '{dir}'");
+        }
+        Ok(Self {
+            name: Cow::Owned(name.to_owned()),
+            dir: Cow::Owned(dir.to_owned()),
+            dimension,
+        })
+    }
+
+    // The flavors of the specification.
     pub fn all() -> impl Iterator<Item = ThumbFlavor> {
         [
-            ThumbFlavor::Normal,
-            ThumbFlavor::Large,
-            ThumbFlavor::XLarge,
-            ThumbFlavor::XXLarge,
+            ThumbFlavor::NORMAL,
+            ThumbFlavor::LARGE,
+            ThumbFlavor::X_LARGE,
+            ThumbFlavor::XX_LARGE,
         ]
-        .iter()
-        .copied()
+        .into_iter()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
     pub fn dimension(&self) -> u32 {
-        match &self {
-            ThumbFlavor::Normal => 128,
-            ThumbFlavor::Large => 256,
-            ThumbFlavor::XLarge => 512,
-            ThumbFlavor::XXLarge => 1024,
-        }
+        self.dimension
     }
 
     pub fn cache_path(&self, cache_dir: &Path) -> PathBuf {
-        cache_dir.join(format!("{}", self))
+        cache_dir.join(&*self.dir)
     }
 }
 
+// ThumbFlavor used to be an enum.
+#[allow(non_upper_case_globals)]
+impl ThumbFlavor {
+    pub const Normal: ThumbFlavor = ThumbFlavor::NORMAL;
+    pub const Large: ThumbFlavor = ThumbFlavor::LARGE;
+    pub const XLarge: ThumbFlavor = ThumbFlavor::X_LARGE;
+    pub const XXLarge: ThumbFlavor = ThumbFlavor::XX_LARGE;
+}
+
+// Only knows about the flavors of the specification, see FlavorSet::get().
 impl TryFrom<&str> for ThumbFlavor {
     type Error = std::io::ErrorKind;
     fn try_from(value: &str) -> Result<Self, Self::Error> {
+        ThumbFlavor::all()
+            .find(|flavor| flavor.name == value)
+            .ok_or(std::io::ErrorKind::InvalidInput)
+    }
+}
+
+impl FromStr for ThumbFlavor {
+    type Err = std::io::ErrorKind;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s)
+    }
+}
+
+impl fmt::Display for ThumbFlavor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl fmt::Debug for ThumbFlavor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({}px)", self.name, self.dimension)
+    }
+}
+
+// The flavors a daemon serves, those of the specification by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlavorSet(Vec<ThumbFlavor>);
+
+impl FlavorSet {
+    pub fn new(flavors: impl IntoIterator<Item = ThumbFlavor>) -> anyhow::Result<Self> {
+        let flavors = flavors.into_iter().collect_vec();
+        if flavors.is_empty() {
+            bail!("at least one flavor is needed");
+        }
+        if let Some(flavor) = flavors.iter().duplicates_by(|flavor| &flavor.name).next() {
+            bail!("flavor '{flavor}' is defined more than once");
+        }
+        if let Some(flavor) = flavors.iter().duplicates_by(|flavor| &flavor.dir).next() {
+            bail!("flavor '{flavor}' shares its directory with another one");
+        }
+        Ok(Self(flavors))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ThumbFlavor> {
+        self.0.iter().find(|flavor| flavor.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ThumbFlavor> {
+        self.0.iter()
+    }
+}
+
+impl Default for FlavorSet {
+    fn default() -> Self {
+        Self(ThumbFlavor::all().collect())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheduler {
+    Foreground,
+    Background,
+}
+
+impl From<&str> for Scheduler {
+    // Unknown schedulers, including the spec's "default", are foreground.
+    fn from(value: &str) -> Self {
         match value {
-            "normal" => Ok(Self::Normal),
-            "large" => Ok(Self::Large),
-            "x-large" => Ok(Self::XLarge),
-            "xx-large" => Ok(Self::XXLarge),
-            _ => Err(std::io::ErrorKind::InvalidInput),
+            "background" | "idle" | "lifo-background" => Self::Background,
+            _ => Self::Foreground,
         }
     }
 }
 
-impl fmt::Display for ThumbFlavor {
+impl fmt::Display for Scheduler {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
             "{}",
             match self {
-                ThumbFlavor::Normal => "normal",
-                ThumbFlavor::Large => "large",
-                ThumbFlavor::XLarge => "x-large",
-                ThumbFlavor::XXLarge => "xx-large",
+                Scheduler::Foreground => "foreground",
+                Scheduler::Background => "background",
             }
         )
     }
 }
 
+// Error codes of the thumbnail management D-Bus specification. The spec's code 1
+// is "connection failed"; it has always been our catch-all for anything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ErrorCode {
+    Unsupported = 0,
+    Failed = 1,
+    InvalidFormat = 2,
+    IsThumbnail = 3,
+    SaveFailed = 4,
+    UnsupportedFlavor = 5,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ErrorCode::Unsupported => "unsupported URI scheme or MIME type",
+                ErrorCode::Failed => "thumbnailing failed",
+                ErrorCode::InvalidFormat => "could not read the original file",
+                ErrorCode::IsThumbnail => "the original file is a thumbnail",
+                ErrorCode::SaveFailed => "could not save the thumbnail",
+                ErrorCode::UnsupportedFlavor => "unsupported flavor",
+            }
+        )
+    }
+}
+
+impl TryFrom<i32> for ErrorCode {
+    type Error = i32;
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ErrorCode::Unsupported),
+            1 => Ok(ErrorCode::Failed),
+            2 => Ok(ErrorCode::InvalidFormat),
+            3 => Ok(ErrorCode::IsThumbnail),
+            4 => Ok(ErrorCode::SaveFailed),
+            5 => Ok(ErrorCode::UnsupportedFlavor),
+            _ => Err(value),
+        }
+    }
+}
+
 pub struct ThumbReply {
     pub handle: u32,
     pub uris: Vec<String>,
 }
 
 pub enum Reply {
+    Started {
+        handle: u32,
+    },
     Ready {
         handle: u32,
         uris: Vec<String>,
+        paths: Vec<PathBuf>,
     },
     Finished {
         handle: u32,
@@ -111,8 +675,81 @@ pub enum Reply {
     Error {
         handle: u32,
         uri: String,
+        code: ErrorCode,
         message: String,
     },
+    // A part of the request handed to a specialized thumbnailer is done.
+    Delegated {
+        handle: u32,
+    },
+    // Acknowledged once all replies sent before it were forwarded.
+    Heartbeat(oneshot::Sender<()>),
+    // Logs the requests that are still live and who queued them.
+    LogClients,
+}
+
+impl Reply {
+    fn handle(&self) -> Option<u32> {
+        match self {
+            Reply::Started { handle }
+            | Reply::Ready { handle, .. }
+            | Reply::Finished { handle }
+            | Reply::Error { handle, .. }
+            | Reply::Delegated { handle } => Some(*handle),
+            Reply::Heartbeat(_) | Reply::LogClients => None,
+        }
+    }
+}
+
+struct SpecializedThumbnailer {
+    owner: String,
+    path: String,
+}
+
+// Specialized thumbnailers registered through Manager1, by URI scheme and MIME
+// type, the latest registration winning. Kept until their owner leaves the
+// bus.
+#[derive(Clone, Default)]
+struct SpecializedThumbnailers(Arc<Mutex<HashMap<(String, String), SpecializedThumbnailer>>>);
+
+impl SpecializedThumbnailers {
+    // Registering again from the same object replaces what it registered
+    // before.
+    fn register(&self, owner: &str, path: &str, types: Vec<(String, String)>) {
+        let mut thumbnailers = self.0.lock().unwrap();
+        thumbnailers
+            .retain(|_, thumbnailer| thumbnailer.owner != owner || thumbnailer.path != path);
+        for key in types {
+            let thumbnailer = SpecializedThumbnailer {
+                owner: owner.to_owned(),
+                path: path.to_owned(),
+            };
+            if let Some(replaced) = thumbnailers.insert(key.clone(), thumbnailer) {
+                debug!(
+                    "{}{} no longer handles {} {}",
+                    replaced.owner, replaced.path, key.0, key.1
+                );
+            }
+        }
+    }
+
+    fn remove_owner(&self, owner: &str) -> usize {
+        let mut thumbnailers = self.0.lock().unwrap();
+        let before = thumbnailers.len();
+        thumbnailers.retain(|_, thumbnailer| thumbnailer.owner != owner);
+        before - thumbnailers.len()
+    }
+
+    // Owner and object path of the thumbnailer for a URI scheme and MIME type.
+    fn find(&self, scheme: &str, mime_type: &str) -> Option<(String, String)> {
+        let thumbnailers = self.0.lock().unwrap();
+        let thumbnailer = thumbnailers.get(&(scheme.to_owned(), mime_type.to_owned()))?;
+        Some((thumbnailer.owner.clone(), thumbnailer.path.clone()))
+    }
+
+    fn supported(&self) -> Vec<(String, String)> {
+        self.0.lock().unwrap().keys().cloned().sorted().collect()
+    }
 }
 
 #[derive(zvariant::Type, serde::Serialize)]
@@ -121,117 +758,444 @@ struct Supported {
     mime_types: Vec<String>,
 }
 
+// How long a Queue call may wait for room in the job channel. Interface
+// methods run on the zbus executor, so this uses its timers, not tokio's.
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Thumbnailer1 {
     req_tx: mpsc::Sender<ThumbJob>,
+    // Weak so that the reply channel closes once the daemon drops its sender.
+    reply_tx: mpsc::WeakSender<Reply>,
     next_handle: atomic::AtomicU32,
+    live_handles: LiveHandles,
+    specialized: SpecializedThumbnailers,
+    flavors: FlavorSet,
+    max_uris: Option<usize>,
+    split_requests: bool,
+    // Queue calls run on the zbus executor, delegations on tokio.
+    runtime: tokio::runtime::Handle,
 }
 
-impl Thumbnailer1 {
-    pub async fn create_and_listen()
-    -> anyhow::Result<(mpsc::Receiver<ThumbJob>, mpsc::Sender<Reply>)> {
-        const WELL_KNOWN_NAME: &str = "org.freedesktop.thumbnails.Thumbnailer1";
-        const INTERFACE_PATH: &str = "/org/freedesktop/thumbnails/Thumbnailer1";
+pub const WELL_KNOWN_NAME: &str = "org.freedesktop.thumbnails.Thumbnailer1";
+pub const INTERFACE_PATH: &str = "/org/freedesktop/thumbnails/Thumbnailer1";
+pub const MANAGER_NAME: &str = "org.freedesktop.thumbnails.Manager1";
+pub const MANAGER_PATH: &str = "/org/freedesktop/thumbnails/Manager1";
+
+// Lets other programs register specialized thumbnailers, which medias of MIME
+// types the daemon cannot handle itself are then delegated to.
+pub struct Manager1 {
+    specialized: SpecializedThumbnailers,
+}
+
+// What the daemon is doing, for debugging; served next to Thumbnailer1.
+pub struct Debug1 {
+    live_handles: LiveHandles,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum BusType {
+    Session,
+    System,
+}
+
+impl fmt::Display for BusType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                BusType::Session => "session",
+                BusType::System => "system",
+            }
+        )
+    }
+}
 
-        const CHANNEL_CAPACITY: usize = 256;
-        let (req_tx, mut req_rx) = mpsc::channel(CHANNEL_CAPACITY);
-        let (job_tx, job_rx) = mpsc::channel(CHANNEL_CAPACITY);
-        let (result_tx, mut result_rx) = mpsc::channel(CHANNEL_CAPACITY);
+#[derive(Debug, Clone)]
+pub struct ListenOptions {
+    pub bus: BusType,
+    pub name: String,
+    pub path: String,
+    // Queue calls wait for room once this many jobs are pending.
+    pub job_capacity: usize,
+    // Replies are forwarded to the bus by a task of their own, so a full
+    // reply channel only slows down processing, never Queue calls.
+    pub reply_capacity: usize,
+    pub flavors: FlavorSet,
+    // Queue calls with more URIs fail, unless `split_requests` is set, in
+    // which case they are split into jobs of at most this many medias.
+    pub max_uris: Option<usize>,
+    pub split_requests: bool,
+}
 
+impl Default for ListenOptions {
+    fn default() -> Self {
+        Self {
+            bus: BusType::Session,
+            name: WELL_KNOWN_NAME.to_owned(),
+            path: INTERFACE_PATH.to_owned(),
+            job_capacity: 256,
+            reply_capacity: 256,
+            flavors: FlavorSet::default(),
+            max_uris: None,
+            split_requests: false,
+        }
+    }
+}
+
+impl Thumbnailer1 {
+    pub async fn create_and_listen(
+        options: &ListenOptions,
+    ) -> anyhow::Result<(
+        mpsc::Receiver<ThumbJob>,
+        mpsc::Sender<Reply>,
+        tokio::task::JoinHandle<()>,
+        LiveHandles,
+    )> {
+        let (req_tx, req_rx) = mpsc::channel(options.job_capacity);
+        let (result_tx, mut result_rx) = mpsc::channel(options.reply_capacity);
+
+        let live_handles = LiveHandles::default();
+        let specialized = SpecializedThumbnailers::default();
         let dbus_thumbnailer = Self {
             req_tx,
+            reply_tx: result_tx.downgrade(),
             next_handle: atomic::AtomicU32::new(1),
+            live_handles: live_handles.clone(),
+            specialized: specialized.clone(),
+            flavors: options.flavors.clone(),
+            max_uris: options.max_uris,
+            split_requests: options.split_requests,
+            runtime: tokio::runtime::Handle::current(),
+        };
+        let manager = Manager1 {
+            specialized: specialized.clone(),
+        };
+        let debug = Debug1 {
+            live_handles: live_handles.clone(),
         };
-        let connection = zbus::connection::Builder::session()?
-            .name(WELL_KNOWN_NAME)?
-            .serve_at(INTERFACE_PATH, dbus_thumbnailer)?
+        let builder = match options.bus {
+            BusType::Session => zbus::connection::Builder::session()?,
+            BusType::System => zbus::connection::Builder::system()?,
+        };
+        let connection = builder
+            .name(options.name.as_str())?
+            .serve_at(options.path.as_str(), dbus_thumbnailer)?
+            .serve_at(options.path.as_str(), debug)?
+            .serve_at(MANAGER_PATH, manager)?
             .build()
-            .await?;
+            .await
+            .map_err(|err| match err {
+                zbus::Error::NameTaken => anyhow!(
+                    "name {} is already owned on the {} bus",
+                    options.name,
+                    options.bus
+                ),
+                zbus::Error::FDO(err) if matches!(*err, fdo::Error::AccessDenied(_)) => anyhow!(
+                    "not allowed to own name {} on the {} bus: {err}",
+                    options.name,
+                    options.bus
+                ),
+                err => err.into(),
+            })?;
+
+        // Only next to the well-known Thumbnailer1, not to test instances, and
+        // without insisting: another thumbnailer may be managing already.
+        let manage = options.name == WELL_KNOWN_NAME;
+        if manage {
+            match connection
+                .request_name_with_flags(MANAGER_NAME, RequestNameFlags::DoNotQueue.into())
+                .await
+            {
+                Ok(RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner) => {}
+                Ok(_) => warn!("{MANAGER_NAME} is already owned, not managing thumbnailers"),
+                Err(err) => warn!("cannot own {MANAGER_NAME}: {err}"),
+            }
+        }
+
+        // Not going through the interface, so that removing it drops it.
+        let signal_emitter = SignalEmitter::new(&connection, options.path.clone())?;
 
-        let object_server = connection.object_server();
-        let interface = object_server
-            .interface::<_, Thumbnailer1>(INTERFACE_PATH)
+        let mut name_lost = fdo::DBusProxy::new(&connection)
+            .await?
+            .receive_name_owner_changed_with_args(&[(2, "")])
             .await?;
+        let abandoned_handles = live_handles.clone();
+        let (watch_connection, path) = (connection.clone(), options.path.clone());
+        let _name_lost_handle = tokio::spawn(async move {
+            while let Some(signal) = name_lost.next().await {
+                let Ok(args) = signal.args() else { continue };
+                if let BusName::Unique(name) = args.name() {
+                    let count = abandoned_handles.abandon_sender(name.as_str());
+                    if count > 0 {
+                        info!("client {name} left the bus, cancelled {count} request(s)");
+                    }
+                    let count = specialized.remove_owner(name.as_str());
+                    if count > 0 {
+                        info!("{name} left the bus, unregistered {count} specialized type(s)");
+                    }
+                }
+            }
+            // The stream only ends once the connection is gone. Dropping the
+            // interface closes the job channel, which stops the daemon.
+            warn!("lost the D-Bus connection");
+            _ = watch_connection
+                .object_server()
+                .remove::<Thumbnailer1, _>(path.as_str())
+                .await;
+        });
 
-        let _handle = tokio::spawn(async move {
-            let dbus_ctx = interface.signal_emitter();
-            loop {
-                tokio::select! {
-                    Some(job) = req_rx.recv() => {
-                        _ = Thumbnailer1::started(dbus_ctx, job.handle).await;
-                        _ = job_tx.send(job).await;
-                    },
-                    Some(res) = result_rx.recv() => match res {
-                        Reply::Ready { handle, uris } => _ = Thumbnailer1::ready(dbus_ctx, handle, &uris).await,
-                        Reply::Finished { handle } => _ = Thumbnailer1::finished(dbus_ctx, handle).await,
-                        Reply::Error { handle, uri, message } => _ = Thumbnailer1::error(dbus_ctx, handle, &uri, 1, &message).await,
+        // Runs until every reply sender is dropped, so awaiting it ensures all
+        // pending signals were emitted.
+        let name = options.name.clone();
+        let forwarded_handles = live_handles.clone();
+        let forwarder = tokio::spawn(async move {
+            let live_handles = forwarded_handles;
+            let dbus_ctx = &signal_emitter;
+            while let Some(res) = result_rx.recv().await {
+                match &res {
+                    Reply::Ready { handle, uris, .. } => live_handles.progress(*handle, uris.len()),
+                    Reply::Error { handle, .. } => live_handles.progress(*handle, 1),
+                    _ => {}
+                }
+                if res
+                    .handle()
+                    .is_some_and(|handle| live_handles.is_abandoned(handle))
+                {
+                    match res {
+                        Reply::Finished { handle } => _ = live_handles.finish(handle),
+                        Reply::Delegated { handle } => _ = live_handles.delegation_done(handle),
+                        _ => {}
+                    }
+                    continue;
+                }
+                // Delegated parts may be ready before the daemon gets to its
+                // own, which must not emit Started again.
+                if let Reply::Started { handle }
+                | Reply::Ready { handle, .. }
+                | Reply::Error { handle, .. } = res
+                {
+                    if live_handles.start(handle) {
+                        _ = Thumbnailer1::started(dbus_ctx, handle).await;
+                    }
+                }
+                match res {
+                    Reply::Heartbeat(ack) => _ = ack.send(()),
+                    Reply::LogClients => live_handles.log(),
+                    Reply::Started { .. } => {}
+                    Reply::Ready {
+                        handle,
+                        uris,
+                        paths,
+                    } => {
+                        _ = Thumbnailer1::ready(dbus_ctx, handle, &uris).await;
+                        // Specialized thumbnailers do not tell where they
+                        // wrote their thumbnails.
+                        if !paths.is_empty() {
+                            let paths: Vec<_> = paths
+                                .iter()
+                                .map(|path| path.to_string_lossy().into_owned())
+                                .collect();
+                            _ = Thumbnailer1::ready_paths(dbus_ctx, handle, &uris, &paths).await;
+                        }
+                    }
+                    Reply::Finished { handle } => {
+                        if live_handles.finish(handle) {
+                            _ = Thumbnailer1::finished(dbus_ctx, handle).await
+                        }
+                    }
+                    Reply::Delegated { handle } => {
+                        if live_handles.delegation_done(handle) {
+                            _ = Thumbnailer1::finished(dbus_ctx, handle).await
+                        }
+                    }
+                    Reply::Error {
+                        handle,
+                        uri,
+                        code,
+                        message,
+                    } => {
+                        _ = Thumbnailer1::error(dbus_ctx, handle, &uri, code as i32, &message).await
                     }
                 }
             }
+            // Everything was sent, let the next instance take over right away.
+            _ = connection.release_name(name.as_str()).await;
+            if manage {
+                _ = connection.release_name(MANAGER_NAME).await;
+            }
         });
 
-        Ok((job_rx, result_tx))
+        Ok((req_rx, result_tx, forwarder, live_handles))
     }
 
-    fn next_handle(&mut self) -> u32 {
+    fn next_handle(&self) -> u32 {
         self.next_handle.fetch_add(1, atomic::Ordering::SeqCst)
     }
 }
 
 #[zbus::interface(name = "org.freedesktop.thumbnails.Thumbnailer1")]
 impl Thumbnailer1 {
+    // The arguments are those of the D-Bus method, plus what zbus injects.
+    #[allow(clippy::too_many_arguments)]
     #[zbus(name = "Queue")]
     async fn queue(
-        &mut self,
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
         uris: Vec<&str>,
         mime_types: Vec<&str>,
         flavor: &str,
-        _scheduler: &str,
-        _handle_to_unqueue: u32,
-    ) -> fdo::Result<u32> {
-        let flavor: ThumbFlavor = ThumbFlavor::try_from(flavor)
-            .map_err(|_| fdo::Error::InvalidArgs(format!("invalid flavor '{flavor}'")))?;
+        scheduler: &str,
+        handle_to_unqueue: u32,
+    ) -> fdo::Result<ResponseDispatchNotifier<u32>> {
+        if uris.len() != mime_types.len() {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "got {} URIs but {} MIME types",
+                uris.len(),
+                mime_types.len()
+            )));
+        }
+        if let Some(max_uris) = self.max_uris.filter(|&max_uris| uris.len() > max_uris) {
+            if !self.split_requests {
+                return Err(fdo::Error::LimitsExceeded(format!(
+                    "got {} URIs, at most {max_uris} are allowed per call",
+                    uris.len()
+                )));
+            }
+        }
+        // Disabled flavors are refused rather than downgraded, so that clients
+        // never get thumbnails smaller than they asked for.
+        let flavor = self
+            .flavors
+            .get(flavor)
+            .cloned()
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("invalid flavor '{flavor}'")))?;
+        // Same semantics as Dequeue; 0 is never a valid handle.
+        if handle_to_unqueue != 0 && !self.live_handles.cancel(handle_to_unqueue) {
+            debug!("ignoring unqueue of unknown handle {handle_to_unqueue}");
+        }
         let handle = self.next_handle();
-        let medias = uris
-            .into_iter()
-            .zip(mime_types)
-            .map(|(uri, mime_type)| MediaRef {
+        if uris.is_empty() {
+            // Nothing to do, but clients still expect the request to finish.
+            // Not before the reply though, or they would not know the handle.
+            let (reply, sent) = ResponseDispatchNotifier::new(handle);
+            let emitter = emitter.into_owned();
+            self.runtime.spawn(async move {
+                sent.await;
+                _ = Thumbnailer1::finished(&emitter, handle).await;
+            });
+            return Ok(reply);
+        }
+        let client = Client::lookup(connection, header.sender()).await;
+        // A URI sent several times is only processed and reported once, using
+        // the MIME type of its first occurrence. Medias of MIME types the
+        // daemon cannot handle go to the specialized thumbnailer registered
+        // for them, if any.
+        let mut medias = Vec::with_capacity(uris.len());
+        let mut delegated: HashMap<(String, String), Vec<MediaRef>> = HashMap::new();
+        for (uri, mime_type) in uris.into_iter().zip(mime_types).unique_by(|(uri, _)| *uri) {
+            let thumbnailer = Some(uri.split_once(':').map_or("", |(scheme, _)| scheme))
+                .filter(|_| !handles_mime_type(mime_type))
+                .and_then(|scheme| self.specialized.find(scheme, mime_type));
+            let media = MediaRef {
                 uri: uri.to_owned(),
                 mime_type: mime_type.to_owned(),
-            })
-            .collect();
-        self.req_tx
-            .send(ThumbJob {
+            };
+            match thumbnailer {
+                Some(thumbnailer) => delegated.entry(thumbnailer).or_default().push(media),
+                None => medias.push(media),
+            }
+        }
+        // Large requests may be split into jobs of their own, which all have
+        // to finish before Finished is emitted for the handle.
+        let n_medias = medias.len();
+        let job_len = match self.max_uris {
+            Some(max_uris) if self.split_requests => max_uris,
+            _ => n_medias.max(1),
+        };
+        let cancel = CancelToken::default();
+        let new_job = |medias| ThumbJob {
+            handle,
+            flavor: flavor.clone(),
+            scheduler: Scheduler::from(scheduler),
+            medias,
+            cancel: cancel.clone(),
+            client: client.clone(),
+        };
+        let mut jobs = Vec::new();
+        while medias.len() > job_len {
+            let rest = medias.split_off(job_len);
+            jobs.push(new_job(medias));
+            medias = rest;
+        }
+        jobs.push(new_job(medias));
+        self.live_handles
+            .insert(handle, &jobs[0], n_medias, jobs.len());
+        for medias in delegated.values() {
+            self.live_handles.delegate(handle, medias.len());
+        }
+        let timeout = async {
+            async_io::Timer::after(QUEUE_TIMEOUT).await;
+            Err(fdo::Error::Failed("too many pending requests".to_owned()))
+        };
+        let send = async {
+            for job in jobs {
+                self.req_tx
+                    .send(job)
+                    .await
+                    .map_err(|_| fdo::Error::Failed("service is shutting down".to_owned()))?;
+            }
+            Ok(())
+        };
+        if let Err(err) = futures_lite::future::or(send, timeout).await {
+            // Jobs that were sent already are skipped.
+            cancel.cancel();
+            self.live_handles.remove(handle);
+            return Err(err);
+        }
+        let flavor = flavor.name().to_owned();
+        for ((owner, path), medias) in delegated {
+            let delegation = Delegation {
+                connection: connection.clone(),
+                owner,
+                path,
                 handle,
-                flavor,
-                medias,
-            })
-            .await
-            .map_err(|_| fdo::Error::Failed(format!("could not send job: {handle}")))?;
-        Ok(handle)
+                flavor: flavor.clone(),
+                scheduler: scheduler.to_owned(),
+                cancel: cancel.clone(),
+                reply_tx: self.reply_tx.clone(),
+            };
+            self.runtime.spawn(delegation.run(medias));
+        }
+        Ok(ResponseDispatchNotifier::new(handle).0)
     }
 
+    // Pending requests are skipped entirely, in-flight ones stop before their
+    // next media. Either way the daemon still emits Finished for the handle.
+    // Unknown handles are ignored: they most likely finished already.
     #[zbus(name = "Dequeue")]
-    async fn dequeue(&self, _handle: u32) -> fdo::Result<()> {
-        Err(fdo::Error::NotSupported(
-            "dequeuing is not implemented".to_owned(),
-        ))
+    async fn dequeue(&self, handle: u32) -> fdo::Result<()> {
+        if !self.live_handles.cancel(handle) {
+            debug!("ignoring dequeue of unknown handle {handle}");
+        }
+        Ok(())
     }
 
     #[zbus(name = "GetSupported")]
     async fn get_supported(&self) -> fdo::Result<Supported> {
-        let schemes = vec!["file".to_owned()];
-        let mime_types: Vec<_> = image::ImageFormat::all()
-            .map(|f| f.to_mime_type().to_owned())
-            .chain(
-                ["image/vnd.microsoft.icon"]
-                    .into_iter()
-                    .map(|s| s.to_owned()),
-            )
-            .dedup()
-            .collect();
-        let it = schemes.into_iter().cartesian_product(mime_types);
-        let schemes = it.clone().map(|(scheme, _)| scheme).collect();
-        let mime_types = it.map(|(_, mime_type)| mime_type).collect();
+        let schemes = SUPPORTED_SCHEMES.iter().map(|s| (*s).to_owned());
+        let mime_types = supported_mime_types();
+        let (schemes, mime_types) = schemes
+            .into_iter()
+            .cartesian_product(mime_types)
+            .chain(self.specialized.supported())
+            .unique()
+            .unzip();
         Ok(Supported {
             schemes,
             mime_types,
@@ -240,7 +1204,7 @@ impl Thumbnailer1 {
 
     #[zbus(name = "GetFlavors")]
     async fn get_flavors(&self) -> fdo::Result<Vec<String>> {
-        Ok(ThumbFlavor::all().map(|f| format!("{f}")).collect())
+        Ok(self.flavors.iter().map(|f| format!("{f}")).collect())
     }
 
     #[zbus(signal, name = "Error")]
@@ -259,9 +1223,269 @@ impl Thumbnailer1 {
         uri: &[String],
     ) -> zbus::Result<()>;
 
+    // Not part of the spec: same as Ready, with the thumbnail path of each URI
+    // so that clients don't have to compute it themselves.
+    #[zbus(signal, name = "ReadyPaths")]
+    pub async fn ready_paths(
+        emitter: &SignalEmitter<'_>,
+        handle: u32,
+        uris: &[String],
+        paths: &[String],
+    ) -> zbus::Result<()>;
+
     #[zbus(signal, name = "Started")]
     pub async fn started(emitter: &SignalEmitter<'_>, handle: u32) -> zbus::Result<()>;
 
     #[zbus(signal, name = "Finished")]
     pub async fn finished(emitter: &SignalEmitter<'_>, handle: u32) -> zbus::Result<()>;
 }
+
+#[zbus::interface(name = "org.freedesktop.thumbnails.Manager1")]
+impl Manager1 {
+    // As in the specification, the two arrays are of the same length, the
+    // thumbnailer handling each of their pairs.
+    #[zbus(name = "Register")]
+    async fn register(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        object_path: &str,
+        uri_schemes: Vec<String>,
+        mime_types: Vec<String>,
+    ) -> fdo::Result<()> {
+        if uri_schemes.len() != mime_types.len() {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "got {} URI schemes but {} MIME types",
+                uri_schemes.len(),
+                mime_types.len()
+            )));
+        }
+        zvariant::ObjectPath::try_from(object_path)
+            .map_err(|_| fdo::Error::InvalidArgs(format!("invalid object path '{object_path}'")))?;
+        let sender = header
+            .sender()
+            .ok_or_else(|| fdo::Error::Failed("no sender".to_owned()))?;
+        let types: Vec<_> = uri_schemes.into_iter().zip(mime_types).collect();
+        info!(
+            "{sender}{object_path} registered as a specialized thumbnailer for {} type(s)",
+            types.len()
+        );
+        self.specialized.register(sender, object_path, types);
+        Ok(())
+    }
+}
+
+#[zbus::interface(name = "org.zopieux.rthumb.Debug1")]
+impl Debug1 {
+    // Pending and in-flight handles with their scheduler, flavor, number of
+    // URIs and number of URIs done.
+    #[zbus(name = "GetQueue")]
+    async fn get_queue(&self) -> fdo::Result<Vec<(u32, String, String, u32, u32)>> {
+        Ok(self.live_handles.queue())
+    }
+}
+
+// Flavors of the specification are serialized as their name, like on the bus,
+// others with their directory and dimension too.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum FlavorRepr {
+    Spec(String),
+    Custom {
+        name: String,
+        directory: String,
+        dimension: u32,
+    },
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for ThumbFlavor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = if ThumbFlavor::all().any(|flavor| flavor == *self) {
+            FlavorRepr::Spec(self.name.to_string())
+        } else {
+            FlavorRepr::Custom {
+                name: self.name.to_string(),
+                directory: self.dir.to_string(),
+                dimension: self.dimension,
+            }
+        };
+        repr.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ThumbFlavor {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        match FlavorRepr::deserialize(deserializer)? {
+            FlavorRepr::Spec(name) => ThumbFlavor::try_from(name.as_str())
+                .map_err(|_| D::Error::custom(format!("unknown flavor '{name}'"))),
+            FlavorRepr::Custom {
+                name,
+                directory,
+                dimension,
+            } => ThumbFlavor::new(&name, &directory, dimension).map_err(D::Error::custom),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Scheduler {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Scheduler {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Scheduler::from(String::deserialize(deserializer)?.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+    };
+
+    use zbus::message::{Message, Type};
+
+    use super::*;
+
+    // A private bus, so that tests neither need nor disturb the session one.
+    struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        fn start() -> Self {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("cannot start dbus-daemon");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            Self {
+                daemon,
+                address: address.trim().to_owned(),
+            }
+        }
+
+        fn builder(&self) -> zbus::connection::Builder<'_> {
+            zbus::connection::Builder::address(self.address.as_str()).unwrap()
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            _ = self.daemon.kill();
+            _ = self.daemon.wait();
+        }
+    }
+
+    async fn serve(bus: &TestBus) -> (zbus::Connection, mpsc::Receiver<ThumbJob>) {
+        let (req_tx, req_rx) = mpsc::channel(16);
+        let thumbnailer = Thumbnailer1 {
+            req_tx,
+            reply_tx: mpsc::channel(1).0.downgrade(),
+            next_handle: atomic::AtomicU32::new(1),
+            live_handles: LiveHandles::default(),
+            specialized: SpecializedThumbnailers::default(),
+            flavors: FlavorSet::default(),
+            max_uris: None,
+            split_requests: false,
+            runtime: tokio::runtime::Handle::current(),
+        };
+        let connection = bus
+            .builder()
+            .serve_at(INTERFACE_PATH, thumbnailer)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        (connection, req_rx)
+    }
+
+    fn queue_call(server: &zbus::Connection, uris: &[&str], mime_types: &[&str]) -> Message {
+        Message::method_call(INTERFACE_PATH, "Queue")
+            .unwrap()
+            .destination(server.unique_name().unwrap().to_owned())
+            .unwrap()
+            .interface(WELL_KNOWN_NAME)
+            .unwrap()
+            .build(&(uris, mime_types, "normal", "default", 0u32))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn queue_rejects_mismatched_lengths() {
+        let bus = TestBus::start();
+        let (server, mut jobs) = serve(&bus).await;
+        let client = bus.builder().build().await.unwrap();
+        let mut messages = MessageStream::from(&client);
+        let call = queue_call(&server, &["file:///a.png", "file:///b.png"], &["image/png"]);
+        client.send(&call).await.unwrap();
+        let serial = call.primary_header().serial_num();
+        let reply = loop {
+            let message = messages.next().await.unwrap().unwrap();
+            if message.header().reply_serial() == Some(serial) {
+                break message;
+            }
+        };
+        assert_eq!(reply.message_type(), Type::Error);
+        assert_eq!(
+            reply.header().error_name().map(|name| name.as_str()),
+            Some("org.freedesktop.DBus.Error.InvalidArgs")
+        );
+        assert!(jobs.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn empty_queue_finishes_after_reply() {
+        let bus = TestBus::start();
+        let (server, mut jobs) = serve(&bus).await;
+        let client = bus.builder().build().await.unwrap();
+        let finished = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface(WELL_KNOWN_NAME)
+            .unwrap()
+            .member("Finished")
+            .unwrap()
+            .build();
+        // Subscribes to the signal, which then shows up in every stream.
+        let _finished = MessageStream::for_match_rule(finished, &client, None)
+            .await
+            .unwrap();
+        let mut messages = MessageStream::from(&client);
+        let call = queue_call(&server, &[], &[]);
+        client.send(&call).await.unwrap();
+        let serial = call.primary_header().serial_num();
+        let mut handle = None;
+        let wait = async {
+            loop {
+                let message = messages.next().await.unwrap().unwrap();
+                let header = message.header();
+                if header.reply_serial() == Some(serial) {
+                    assert_eq!(message.message_type(), Type::MethodReturn);
+                    handle = Some(message.body().deserialize::<u32>().unwrap());
+                } else if header.member().is_some_and(|member| member == "Finished") {
+                    // Clients drop signals for handles they do not know yet.
+                    assert!(handle.is_some(), "Finished was emitted before the reply");
+                    assert_eq!(message.body().deserialize::<u32>().ok(), handle);
+                    break;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .expect("Finished was not emitted");
+        assert!(jobs.try_recv().is_err());
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    ffi::OsStr,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, anyhow, bail};
+use image::DynamicImage;
+use log::{debug, warn};
+
+use crate::xdg::{data_dirs, data_home};
+
+// When external thumbnailers are used, relative to our own decoders.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecMode {
+    #[default]
+    Off,
+    // Only for files our decoders do not support.
+    Fallback,
+    // Whenever one handles the MIME type.
+    Preferred,
+}
+
+// A .thumbnailer file, as shipped by distributions in
+// /usr/share/thumbnailers.
+#[derive(Debug, Clone)]
+pub struct ExecThumbnailer {
+    pub name: String,
+    // Exec line split into arguments, field codes left in.
+    pub args: Vec<String>,
+    pub mime_types: Vec<String>,
+}
+
+impl ExecThumbnailer {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let (mut exec, mut try_exec, mut mime_types) = (None, None, Vec::new());
+        let mut in_entry = false;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                in_entry = line == "[Thumbnailer Entry]";
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            if !in_entry {
+                continue;
+            }
+            match key.trim() {
+                "Exec" => exec = Some(unescape_value(value.trim())),
+                "TryExec" => try_exec = Some(unescape_value(value.trim())),
+                "MimeType" => {
+                    mime_types = value
+                        .split(';')
+                        .map(str::trim)
+                        .filter(|mime_type| !mime_type.is_empty())
+                        .map(str::to_owned)
+                        .collect();
+                }
+                _ => {}
+            }
+        }
+        let exec = exec.ok_or_else(|| anyhow!("no Exec key"))?;
+        let args = split_exec(&exec)?;
+        let program = args.first().ok_or_else(|| anyhow!("empty Exec key"))?;
+        for program in try_exec.iter().chain([program]) {
+            if find_program(program).is_none() {
+                bail!("{program} is not installed");
+            }
+        }
+        Ok(Self {
+            name,
+            args,
+            mime_types,
+        })
+    }
+
+    // Runs the thumbnailer on the file at `path`, returning the image it
+    // wrote, which may be larger than asked for.
+    pub fn run(
+        &self,
+        path: &Path,
+        uri: &str,
+        size: u32,
+        timeout: Duration,
+    ) -> anyhow::Result<DynamicImage> {
+        let output = TempOutput::create()?;
+        let mut args = Vec::with_capacity(self.args.len());
+        for arg in &self.args {
+            args.push(substitute(arg, path, uri, &output.0, size)?);
+        }
+        let mut child = Command::new(&args[0])
+            .args(&args[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("cannot run the {} thumbnailer", self.name))?;
+        // Read meanwhile, or a chatty thumbnailer would block on a full pipe.
+        let stderr = child.stderr.take().map(|mut stderr| {
+            std::thread::spawn(move || {
+                let mut text = String::new();
+                _ = stderr.read_to_string(&mut text);
+                text
+            })
+        });
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                _ = child.kill();
+                _ = child.wait();
+                bail!("the {} thumbnailer timed out after {timeout:?}", self.name);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let stderr = stderr
+            .and_then(|reader| reader.join().ok())
+            .unwrap_or_default();
+        if !status.success() {
+            let stderr = stderr.trim();
+            if stderr.is_empty() {
+                bail!("the {} thumbnailer failed ({status})", self.name);
+            }
+            bail!("the {} thumbnailer failed ({status}): {stderr}", self.name);
+        }
+        if !std::fs::metadata(&output.0).is_ok_and(|meta| meta.len() > 0) {
+            bail!("the {} thumbnailer wrote no thumbnail", self.name);
+        }
+        let im = image::ImageReader::open(&output.0)?
+            .with_guessed_format()?
+            .decode()
+            .with_context(|| format!("cannot read the output of the {} thumbnailer", self.name))?;
+        Ok(im)
+    }
+}
+
+// Reserved before the thumbnailer runs, so that nobody else can create it,
+// and removed once read.
+struct TempOutput(PathBuf);
+
+impl TempOutput {
+    fn create() -> anyhow::Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rthumbd-{}-{}.png",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::File::create_new(&path).with_context(|| format!("cannot create {path:?}"))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempOutput {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// Undoes the escapes of desktop entry string values.
+fn unescape_value(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => result.push(' '),
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some(c) => result.push(c),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+// Splits an Exec value into arguments, following the quoting rules of the
+// desktop entry specification.
+fn split_exec(exec: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut chars = exec.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '`' | '$' | '\\')) => arg.push(c),
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                            None => bail!("unterminated quote in '{exec}'"),
+                        },
+                        Some(c) => arg.push(c),
+                        None => bail!("unterminated quote in '{exec}'"),
+                    }
+                }
+            }
+            c if c.is_whitespace() => args.extend(arg.take()),
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+// Replaces the field codes of a single argument, which keeps paths with
+// spaces in one piece.
+fn substitute(
+    arg: &str,
+    path: &Path,
+    uri: &str,
+    output: &Path,
+    size: u32,
+) -> anyhow::Result<std::ffi::OsString> {
+    let mut result = std::ffi::OsString::new();
+    let mut rest = arg;
+    while let Some(start) = rest.find('%') {
+        result.push(&rest[..start]);
+        let code = rest[start + 1..].chars().next();
+        match code {
+            Some('i') => result.push(path),
+            Some('u') => result.push(uri),
+            Some('o') => result.push(output),
+            Some('s') => result.push(size.to_string()),
+            Some('%') => result.push("%"),
+            Some(code) => bail!("unsupported field code %{code} in '{arg}'"),
+            None => bail!("trailing % in '{arg}'"),
+        }
+        rest = &rest[start + 1 + code.map_or(0, char::len_utf8)..];
+    }
+    result.push(rest);
+    Ok(result)
+}
+
+fn find_program(program: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path)
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+}
+
+// Where .thumbnailer files are looked for, most important first.
+pub fn default_dirs() -> Vec<PathBuf> {
+    data_home()
+        .into_iter()
+        .chain(data_dirs())
+        .map(|dir| dir.join("thumbnailers"))
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct ExecThumbnailers {
+    pub mode: ExecMode,
+    pub timeout: Duration,
+    thumbnailers: Vec<ExecThumbnailer>,
+}
+
+impl ExecThumbnailers {
+    // Thumbnailers of earlier directories win for the MIME types they share
+    // with later ones. Broken or uninstalled ones are skipped.
+    pub fn load(dirs: &[PathBuf], mode: ExecMode, timeout: Duration) -> Self {
+        let mut thumbnailers = Vec::new();
+        for dir in dirs {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            let mut paths: Vec<_> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension() == Some(OsStr::new("thumbnailer")))
+                .collect();
+            paths.sort();
+            for path in paths {
+                match ExecThumbnailer::load(&path) {
+                    // Would call us again.
+                    Ok(thumbnailer)
+                        if Path::new(&thumbnailer.args[0]).file_name()
+                            == Some(OsStr::new("rthumbd")) => {}
+                    Ok(thumbnailer) => thumbnailers.push(thumbnailer),
+                    Err(err) => debug!("skipping {path:?}: {err:#}"),
+                }
+            }
+        }
+        Self {
+            mode,
+            timeout,
+            thumbnailers,
+        }
+    }
+
+    pub fn find(&self, mime_type: &str) -> Option<&ExecThumbnailer> {
+        self.thumbnailers
+            .iter()
+            .find(|thumbnailer| thumbnailer.mime_types.iter().any(|m| m == mime_type))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ExecThumbnailer> {
+        self.thumbnailers.iter()
+    }
+}
+
+static THUMBNAILERS: OnceLock<ExecThumbnailers> = OnceLock::new();
+
+// Makes external thumbnailers available to process_item(); they are not used
+// until this is called.
+pub fn init(thumbnailers: ExecThumbnailers) {
+    if THUMBNAILERS.set(thumbnailers).is_err() {
+        warn!("external thumbnailers were already set up");
+    }
+}
+
+pub fn get() -> Option<&'static ExecThumbnailers> {
+    THUMBNAILERS
+        .get()
+        .filter(|thumbnailers| thumbnailers.mode != ExecMode::Off)
+}
@@ -0,0 +1,98 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, anyhow};
+use rthumbd::{dbus, xdg::data_home};
+
+const SYSTEMD_UNIT_NAME: &str = "rthumbd.service";
+
+struct ActivationFile {
+    path: PathBuf,
+    contents: String,
+}
+
+fn activation_files(bus_name: &str, systemd: bool) -> anyhow::Result<Vec<ActivationFile>> {
+    let data_home = data_home()?;
+    let exe = std::env::current_exe().context("cannot locate the rthumbd executable")?;
+    let mut exec = exe
+        .to_str()
+        .ok_or_else(|| anyhow!("executable path is not valid UTF-8: {exe:?}"))?
+        .to_owned();
+    if bus_name != dbus::WELL_KNOWN_NAME {
+        exec.push_str(&format!(" --bus-name {bus_name}"));
+    }
+
+    let mut dbus_service = format!("[D-BUS Service]\nName={bus_name}\nExec={exec}\n");
+    if systemd {
+        // Lets D-Bus activation go through systemd, which then owns the process.
+        dbus_service.push_str(&format!("SystemdService={SYSTEMD_UNIT_NAME}\n"));
+    }
+    let mut files = vec![ActivationFile {
+        path: dbus_service_path(&data_home, bus_name),
+        contents: dbus_service,
+    }];
+    if systemd {
+        files.push(ActivationFile {
+            path: systemd_unit_path(&data_home),
+            contents: format!(
+                "[Unit]\nDescription=Thumbnailing service\n\n\
+                 [Service]\nType=dbus\nBusName={bus_name}\nExecStart={exec}\n"
+            ),
+        });
+    }
+    Ok(files)
+}
+
+fn dbus_service_path(data_home: &Path, bus_name: &str) -> PathBuf {
+    data_home
+        .join("dbus-1")
+        .join("services")
+        .join(format!("{bus_name}.service"))
+}
+
+fn systemd_unit_path(data_home: &Path) -> PathBuf {
+    data_home
+        .join("systemd")
+        .join("user")
+        .join(SYSTEMD_UNIT_NAME)
+}
+
+pub fn install(bus_name: &str, systemd: bool, force: bool) -> anyhow::Result<()> {
+    let files = activation_files(bus_name, systemd)?;
+    if !force {
+        if let Some(file) = files.iter().find(|file| file.path.exists()) {
+            return Err(anyhow!(
+                "{} already exists, use --force to overwrite it",
+                file.path.display()
+            ));
+        }
+    }
+    for file in files {
+        if let Some(parent) = file.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("cannot create {}", parent.display()))?;
+        }
+        std::fs::File::create(&file.path)
+            .and_then(|mut f| f.write_all(file.contents.as_bytes()))
+            .with_context(|| format!("cannot write {}", file.path.display()))?;
+        println!("wrote {}:\n{}", file.path.display(), file.contents);
+    }
+    Ok(())
+}
+
+pub fn uninstall(bus_name: &str) -> anyhow::Result<()> {
+    let data_home = data_home()?;
+    for path in [
+        dbus_service_path(&data_home, bus_name),
+        systemd_unit_path(&data_home),
+    ] {
+        match std::fs::remove_file(&path) {
+            Ok(()) => println!("removed {}", path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).context(format!("cannot remove {}", path.display())),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use std::{fmt, path::Path};
+
+use anyhow::{Context, anyhow};
+use image::{DynamicImage, ImageFormat, RgbImage};
+use zune_core::{colorspace::ColorSpace, options::DecoderOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorModel {
+    Grayscale,
+    YCbCr,
+    Rgb,
+    Cmyk,
+    Ycck,
+}
+
+impl fmt::Display for ColorModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ColorModel::Grayscale => "grayscale",
+                ColorModel::YCbCr => "YCbCr",
+                ColorModel::Rgb => "RGB",
+                ColorModel::Cmyk => "CMYK",
+                ColorModel::Ycck => "YCCK",
+            }
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct JpegInfo {
+    pub components: u8,
+    pub adobe_transform: Option<u8>,
+}
+
+impl JpegInfo {
+    pub fn read(data: &[u8]) -> anyhow::Result<Self> {
+        if !data.starts_with(&[0xFF, 0xD8]) {
+            return Err(anyhow!("not a JPEG file"));
+        }
+        let mut components = None;
+        let mut adobe_transform = None;
+        let mut pos = 2;
+        while pos + 4 <= data.len() {
+            if data[pos] != 0xFF {
+                return Err(anyhow!("corrupt JPEG marker at offset {pos}"));
+            }
+            let marker = data[pos + 1];
+            if marker == 0xFF {
+                // Fill byte.
+                pos += 1;
+                continue;
+            }
+            if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+                pos += 2;
+                continue;
+            }
+            let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+            let segment = data
+                .get(pos + 4..pos + 2 + length)
+                .ok_or(anyhow!("truncated JPEG segment at offset {pos}"))?;
+            match marker {
+                // APP14, see https://exiftool.org/TagNames/JPEG.html#Adobe
+                0xEE if segment.len() >= 12 && segment.starts_with(b"Adobe") => {
+                    adobe_transform = Some(segment[11]);
+                }
+                // SOFn, excluding DHT, JPG and DAC which share the range.
+                0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                    components = segment.get(5).copied();
+                }
+                // SOS: entropy-coded data follows, all headers have been seen.
+                0xDA => break,
+                _ => {}
+            }
+            pos += 2 + length;
+        }
+        Ok(Self {
+            components: components.ok_or(anyhow!("missing JPEG frame header"))?,
+            adobe_transform,
+        })
+    }
+
+    pub fn color_model(&self) -> ColorModel {
+        match (self.components, self.adobe_transform) {
+            (1, _) => ColorModel::Grayscale,
+            (3, Some(0)) => ColorModel::Rgb,
+            (3, _) => ColorModel::YCbCr,
+            (_, Some(2)) => ColorModel::Ycck,
+            _ => ColorModel::Cmyk,
+        }
+    }
+
+    // Adobe applications write CMYK and YCCK with inverted channels, and
+    // the APP14 marker is how readers know about it.
+    pub fn is_adobe_inverted(&self) -> bool {
+        self.adobe_transform.is_some()
+    }
+}
+
+pub fn open(path: &Path) -> anyhow::Result<DynamicImage> {
+    let data = std::fs::read(path)?;
+    let info = JpegInfo::read(&data)?;
+    match info.color_model() {
+        model @ (ColorModel::Cmyk | ColorModel::Ycck) => decode_four_channel(&data, &info)
+            .with_context(|| format!("failed to decode {model} JPEG")),
+        _ => Ok(image::load_from_memory_with_format(
+            &data,
+            ImageFormat::Jpeg,
+        )?),
+    }
+}
+
+fn decode_four_channel(data: &[u8], info: &JpegInfo) -> anyhow::Result<DynamicImage> {
+    let model = info.color_model();
+    // Request the input color space as output so the decoder hands back the
+    // raw channels instead of applying its own conversion.
+    let colorspace = match model {
+        ColorModel::Ycck => ColorSpace::YCCK,
+        _ => ColorSpace::CMYK,
+    };
+    let options = DecoderOptions::default()
+        .jpeg_set_out_colorspace(colorspace)
+        .set_strict_mode(false)
+        .set_max_width(usize::MAX)
+        .set_max_height(usize::MAX);
+    let mut decoder = zune_jpeg::JpegDecoder::new_with_options(data, options);
+    let pixels = decoder.decode()?;
+    let (width, height) = decoder.dimensions().ok_or(anyhow!("missing dimensions"))?;
+    let inverted = info.is_adobe_inverted();
+    let rgb: Vec<u8> = pixels
+        .chunks_exact(4)
+        .flat_map(|px| {
+            let [c, m, y, k] = match model {
+                ColorModel::Ycck => ycck_to_cmyk(px),
+                _ => [px[0], px[1], px[2], px[3]],
+            };
+            cmyk_to_rgb([c, m, y, k], inverted)
+        })
+        .collect();
+    let image = RgbImage::from_raw(width as u32, height as u32, rgb)
+        .ok_or(anyhow!("decoded buffer does not match dimensions"))?;
+    Ok(DynamicImage::ImageRgb8(image))
+}
+
+fn ycck_to_cmyk(px: &[u8]) -> [u8; 4] {
+    let y = px[0] as f32;
+    let cb = px[1] as f32 - 128.0;
+    let cr = px[2] as f32 - 128.0;
+    let r = y + 1.402 * cr;
+    let g = y - 0.344_136 * cb - 0.714_136 * cr;
+    let b = y + 1.772 * cb;
+    let clamp = |v: f32| v.round().clamp(0.0, 255.0) as u8;
+    [255 - clamp(r), 255 - clamp(g), 255 - clamp(b), px[3]]
+}
+
+// Naive conversion, without any color profile.
+fn cmyk_to_rgb(cmyk: [u8; 4], inverted: bool) -> [u8; 3] {
+    let [c, m, y, k] = cmyk.map(|v| if inverted { v as u32 } else { 255 - v as u32 });
+    [c, m, y].map(|v| (v * k / 255) as u8)
+}
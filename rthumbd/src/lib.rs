@@ -1,2 +1,10 @@
+pub mod cache;
 pub mod dbus;
+pub mod exec;
+pub mod jpeg;
+pub mod redact;
+pub mod sandbox;
+pub mod stats;
+pub mod thumbnail;
+pub mod validated;
 pub mod xdg;
@@ -1,161 +1,1536 @@
-use std::path::{Path, PathBuf};
+use std::{
+    any::Any,
+    collections::{HashMap, VecDeque, hash_map::Entry},
+    net::SocketAddr,
+    num::NonZeroUsize,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::RecvTimeoutError,
+    },
+    time::{Duration, SystemTime},
+};
 
 use anyhow::anyhow;
-use image::EncodableLayout;
-use itertools::{
-    Either::{Left, Right},
-    Itertools,
-};
+use clap::{ArgAction, Parser, Subcommand, builder::BoolishValueParser};
+use itertools::Itertools;
 use log::{debug, info, warn};
 use rayon::iter::ParallelIterator;
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator};
 use rthumbd::{
-    dbus::{self, MediaRef, Reply, ThumbFlavor},
+    cache::{self, CleanupOptions},
+    dbus::{
+        self, BusType, CancelToken, ErrorCode, ListenOptions, MediaRef, Reply, Scheduler,
+        ThumbFlavor, ThumbJob,
+    },
+    exec::{self, ExecMode},
+    redact::{self, DisplayUri},
+    sandbox::{self, SandboxLevel, SandboxMode, SandboxOptions},
+    stats::STATS,
+    thumbnail::{
+        Options, ThumbError, ThumbSource, decoders_by_mime_type, process_item, render_thumbnail,
+        thumbnail_file, write_image,
+    },
     xdg::{
-        ThumbFsMeta, ThumbFullMeta, cache_destination, destination_filename,
-        get_thumb_original_metadata, temp_filename, write_thumb_with_original_metadata,
+        FAIL_APP_NAME, PngCompression, ThumbFsMeta, cache_destination, canonical_uri,
+        create_private_dir_all, fail_dir, legacy_cache_destination,
     },
 };
-use tokio::sync::mpsc;
+use tokio::{
+    signal::unix::{Signal, SignalKind, signal},
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc, oneshot, watch},
+    task::JoinSet,
+    time::Instant,
+};
+
+mod config;
+mod install;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "watch")]
+mod watcher;
+mod worker;
+
+use config::Config;
+
+// Options left out fall back to the environment variable if any, then the
+// configuration file, then the default.
+#[derive(Parser, Debug)]
+#[command(version, about = "Thumbnailing D-Bus service")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Configuration file [default: $XDG_CONFIG_HOME/rthumb/config.toml]
+    #[arg(long, env = "RTHUMB_CONFIG")]
+    config: Option<PathBuf>,
+    /// Print the effective configuration and exit.
+    #[arg(long)]
+    check_config: bool,
+    /// Bus to serve on [default: session]
+    #[arg(long, value_enum)]
+    bus: Option<BusType>,
+    /// Well-known name to own on the bus [default:
+    /// org.freedesktop.thumbnails.Thumbnailer1]
+    #[arg(long)]
+    bus_name: Option<String>,
+    /// Thumbnail cache directory [default: $XDG_CACHE_HOME/thumbnails]
+    #[arg(long, env = "RTHUMB_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+    /// Number of thumbnailing threads [default: number of CPUs]
+    #[arg(long, env = "RAYON_NUM_THREADS")]
+    threads: Option<NonZeroUsize>,
+    /// Number of threads for background requests, which get a pool of their
+    /// own [default: half the number of threads]
+    #[arg(long)]
+    background_threads: Option<NonZeroUsize>,
+    /// Niceness added to the threads of background requests, which also only
+    /// get the I/O time nothing else wants unless 0 [default: 10]
+    #[arg(long, value_parser = clap::value_parser!(i32).range(0..=19))]
+    background_niceness: Option<i32>,
+    /// Maximum number of medias of a request a thread takes on at once
+    /// [default: 2]
+    #[arg(long, env = "RTHUMB_CHUNK_SIZE")]
+    chunk_size: Option<NonZeroUsize>,
+    /// Sniff the file content when the MIME type is missing or unknown
+    /// [default: false]
+    #[arg(
+        long,
+        env = "RTHUMB_MIME_SNIFFING",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new()
+    )]
+    mime_sniffing: Option<bool>,
+    /// Log more, can be repeated; overrides RUST_LOG.
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Seconds to let in-flight requests wind down on SIGTERM/SIGINT before
+    /// exiting anyway [default: 10]
+    #[arg(long)]
+    drain_timeout: Option<u64>,
+    /// Seconds without any request after which to exit, relying on D-Bus
+    /// activation to start again; 0 to never exit [default: 300]
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+    /// Maximum number of requests processed at once [default: number of
+    /// CPUs divided by the chunk size]
+    #[arg(long)]
+    max_requests: Option<NonZeroUsize>,
+    /// Maximum number of URIs of a single Queue call; 0 for no limit
+    /// [default: 10000]
+    #[arg(long)]
+    max_queue_uris: Option<usize>,
+    /// Split Queue calls with more URIs than --max-queue-uris into several
+    /// jobs, reported under the same handle, rather than refusing them
+    /// [default: false]
+    #[arg(
+        long,
+        value_parser = BoolishValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    split_large_queues: Option<bool>,
+    /// Seconds between cache cleanups, run while no request is being
+    /// processed; 0 to never clean up [default: 0]
+    #[arg(long)]
+    cleanup_interval: Option<u64>,
+    /// Days after which cache cleanups remove thumbnails even if their
+    /// original still exists [default: never]
+    #[arg(long)]
+    cleanup_max_age: Option<u64>,
+    /// Megabytes the cache may take up before the least recently used
+    /// thumbnails get evicted, checked at most every 30s once idle; 0 for no
+    /// limit [default: 0]
+    #[arg(long)]
+    cache_quota: Option<u64>,
+    /// Reuse up-to-date thumbnails from the legacy ~/.thumbnails directory
+    /// rather than rendering them again.
+    #[arg(
+        long,
+        value_parser = BoolishValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    legacy_cache_fallback: Option<bool>,
+    /// Move the legacy ~/.thumbnails directory into the cache directory on
+    /// startup, leaving a symbolic link behind.
+    #[arg(
+        long,
+        value_parser = BoolishValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    migrate_legacy_cache: Option<bool>,
+    /// Also read and write thumbnails of files on other file systems, such as
+    /// removable media, in .sh_thumbnails directories next to them.
+    #[arg(
+        long,
+        value_parser = BoolishValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    shared_repositories: Option<bool>,
+    /// Restrict cache directories accessible to other users to their owner
+    /// on startup, rather than only warning about them.
+    #[arg(
+        long,
+        value_parser = BoolishValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    fix_permissions: Option<bool>,
+    /// Also regenerate thumbnails when the original's inode or ctime
+    /// changed, e.g. when replaced by a copy with the same mtime.
+    #[arg(
+        long,
+        value_parser = BoolishValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    strict_validation: Option<bool>,
+    /// Seconds after which to give up on a single media, leaving it to
+    /// finish in the background; 0 to wait forever [default: 0]
+    #[arg(long)]
+    media_timeout: Option<u64>,
+    /// Seconds during which to fail medias without decoding them once the
+    /// cache's file system is found full or read-only; 0 to always try
+    /// [default: 60]
+    #[arg(long)]
+    cache_full_cooldown: Option<u64>,
+    /// Megabytes above which originals are not thumbnailed; 0 for no limit
+    /// [default: 0]
+    #[arg(long)]
+    max_source_size: Option<u64>,
+    /// Number of up-to-date thumbnails to remember, to check them again
+    /// without reading them; 0 to always read them [default: 4096]
+    #[arg(long)]
+    validation_cache_size: Option<usize>,
+    /// PNG compression of thumbnails [default: fast]
+    #[arg(long, value_enum)]
+    png_compression: Option<PngCompression>,
+    /// Pick a PNG filter for each row of thumbnails, making them smaller but
+    /// slower to write.
+    #[arg(
+        long,
+        value_parser = BoolishValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    png_adaptive_filter: Option<bool>,
+    /// Flush thumbnails to disk before renaming them into place, so that
+    /// power losses cannot leave truncated ones behind.
+    #[arg(
+        long,
+        value_parser = BoolishValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    durable_writes: Option<bool>,
+    /// Write thumbnails up to this many pixels across with a palette of 256
+    /// colors when that looks close enough, e.g. 128 for the normal flavor;
+    /// 0 to never [default: 0]
+    #[arg(long)]
+    palette_max_size: Option<u32>,
+    /// Comma-separated flavors to refuse requests for, and to leave out of
+    /// GetFlavors [default: none]
+    #[arg(long, value_delimiter = ',')]
+    disabled_flavors: Option<Vec<String>>,
+    /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9464; needs the
+    /// metrics feature [default: none]
+    #[arg(long)]
+    metrics_address: Option<SocketAddr>,
+    /// Hide file names from logs, showing a hash and the extension instead.
+    #[arg(
+        long,
+        value_parser = BoolishValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    redact_uris: Option<bool>,
+    /// Run the .thumbnailer files of other programs for files our decoders
+    /// do not support, or for any file they handle [default: off]
+    #[arg(long, value_enum)]
+    exec_thumbnailers: Option<ExecMode>,
+    /// Seconds after which to kill external thumbnailers [default: 30]
+    #[arg(long)]
+    exec_timeout: Option<u64>,
+    /// Colon-separated directories to look for .thumbnailer files in
+    /// [default: the thumbnailers directories of $XDG_DATA_HOME and
+    /// $XDG_DATA_DIRS]
+    #[arg(long, value_delimiter = ':')]
+    thumbnailer_dirs: Option<Vec<PathBuf>>,
+    /// Directories of recently thumbnailed files to watch, removing the
+    /// thumbnails of files that change, 0 to not watch any; needs the watch
+    /// feature [default: 0]
+    #[arg(long)]
+    watch_dirs: Option<usize>,
+    /// Decode in subprocesses, one per thread, so that a crashing decoder
+    /// only fails the media it was reading rather than the whole daemon.
+    #[arg(
+        long,
+        value_parser = BoolishValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    isolate_decoders: Option<bool>,
+    /// Once set up, only write to the cache, and with strict, also refuse
+    /// syscalls decoders never need; falls back to what the kernel supports
+    /// [default: off]
+    #[arg(long, value_enum)]
+    sandbox: Option<SandboxMode>,
+}
+
+impl Args {
+    fn apply(self, config: &mut Config) {
+        config.bus = self.bus.unwrap_or(config.bus);
+        config.bus_name = self
+            .bus_name
+            .unwrap_or(std::mem::take(&mut config.bus_name));
+        config.chunk_size = self.chunk_size.unwrap_or(config.chunk_size);
+        config.mime_sniffing = self.mime_sniffing.unwrap_or(config.mime_sniffing);
+        config.drain_timeout = self.drain_timeout.unwrap_or(config.drain_timeout);
+        config.idle_timeout = self.idle_timeout.unwrap_or(config.idle_timeout);
+        config.cache_dir = self.cache_dir.or(config.cache_dir.take());
+        config.threads = self.threads.or(config.threads);
+        config.background_threads = self.background_threads.or(config.background_threads);
+        config.background_niceness = self
+            .background_niceness
+            .unwrap_or(config.background_niceness);
+        config.max_requests = self.max_requests.or(config.max_requests);
+        config.max_queue_uris = self.max_queue_uris.unwrap_or(config.max_queue_uris);
+        config.split_large_queues = self.split_large_queues.unwrap_or(config.split_large_queues);
+        config.cleanup_interval = self.cleanup_interval.unwrap_or(config.cleanup_interval);
+        config.cleanup_max_age = self.cleanup_max_age.or(config.cleanup_max_age);
+        config.cache_quota = self.cache_quota.unwrap_or(config.cache_quota);
+        config.legacy_cache_fallback = self
+            .legacy_cache_fallback
+            .unwrap_or(config.legacy_cache_fallback);
+        config.migrate_legacy_cache = self
+            .migrate_legacy_cache
+            .unwrap_or(config.migrate_legacy_cache);
+        config.shared_repositories = self
+            .shared_repositories
+            .unwrap_or(config.shared_repositories);
+        config.fix_permissions = self.fix_permissions.unwrap_or(config.fix_permissions);
+        config.strict_validation = self.strict_validation.unwrap_or(config.strict_validation);
+        config.media_timeout = self.media_timeout.unwrap_or(config.media_timeout);
+        config.cache_full_cooldown = self
+            .cache_full_cooldown
+            .unwrap_or(config.cache_full_cooldown);
+        config.max_source_size = self.max_source_size.unwrap_or(config.max_source_size);
+        config.validation_cache_size = self
+            .validation_cache_size
+            .unwrap_or(config.validation_cache_size);
+        config.png_compression = self.png_compression.unwrap_or(config.png_compression);
+        config.png_adaptive_filter = self
+            .png_adaptive_filter
+            .unwrap_or(config.png_adaptive_filter);
+        config.durable_writes = self.durable_writes.unwrap_or(config.durable_writes);
+        config.palette_max_size = self.palette_max_size.unwrap_or(config.palette_max_size);
+        config.disabled_flavors = self
+            .disabled_flavors
+            .unwrap_or(std::mem::take(&mut config.disabled_flavors));
+        config.metrics_address = self.metrics_address.or(config.metrics_address);
+        config.redact_uris = self.redact_uris.unwrap_or(config.redact_uris);
+        config.exec_thumbnailers = self.exec_thumbnailers.unwrap_or(config.exec_thumbnailers);
+        config.exec_timeout = self.exec_timeout.unwrap_or(config.exec_timeout);
+        config.thumbnailer_dirs = self
+            .thumbnailer_dirs
+            .unwrap_or(std::mem::take(&mut config.thumbnailer_dirs));
+        config.watch_dirs = self.watch_dirs.unwrap_or(config.watch_dirs);
+        config.isolate_decoders = self.isolate_decoders.unwrap_or(config.isolate_decoders);
+        config.sandbox = self.sandbox.unwrap_or(config.sandbox);
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write the D-Bus activation file for this executable under
+    /// $XDG_DATA_HOME.
+    Install {
+        /// Also write a systemd user unit, and activate through it.
+        #[arg(long)]
+        systemd: bool,
+        /// Overwrite existing files.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Remove the files written by install.
+    Uninstall,
+    /// Remove leftover temporary files, thumbnails of files that no longer
+    /// exist, unreadable thumbnails, and those older than --cleanup-max-age
+    /// days, then evict thumbnails until the cache fits in --cache-quota.
+    Cleanup {
+        /// Only remove thumbnails written by rthumbd.
+        #[arg(long)]
+        only_ours: bool,
+    },
+    /// Thumbnail the given files and exit, without D-Bus.
+    Once {
+        /// Thumbnail size, one of the configured flavors.
+        #[arg(long, default_value = "normal")]
+        flavor: String,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Thumbnail a single file to the given path, following the calling
+    /// convention of .thumbnailer files: `Exec=rthumbd thumbnail %i %o %s`.
+    Thumbnail {
+        /// File to thumbnail, as a path or a file:// URI.
+        input: String,
+        /// Where to write the PNG thumbnail.
+        output: PathBuf,
+        /// Largest dimension of the thumbnail, in pixels.
+        #[arg(default_value_t = 256)]
+        size: u32,
+    },
+    /// Thumbnail medias sent on stdin on behalf of the daemon, see
+    /// --isolate-decoders.
+    #[command(hide = true)]
+    Worker,
+}
+
+#[derive(Clone)]
+enum Outcome {
+    Ready {
+        uri: String,
+        path: PathBuf,
+        source: ThumbSource,
+    },
+    Error {
+        uri: String,
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl Outcome {
+    // The same outcome, for another spelling of the URI.
+    fn with_uri(&self, uri: String) -> Self {
+        match self.clone() {
+            Outcome::Ready { path, source, .. } => Outcome::Ready { uri, path, source },
+            Outcome::Error { code, message, .. } => Outcome::Error { uri, code, message },
+        }
+    }
+}
+
+// Thumbnails currently being generated, along with the outcome channels of
+// other requests that asked for the same thumbnail in the meantime. Keyed on
+// the canonical URI, as spellings of it share the thumbnail and its temporary
+// files; waiters are answered with the URI they sent.
+type InFlightKey = (String, ThumbFlavor);
+type InFlightWaiter = (mpsc::Sender<Outcome>, String);
+
+#[derive(Default)]
+struct InFlight(Mutex<HashMap<InFlightKey, Vec<InFlightWaiter>>>);
+
+impl InFlight {
+    // Returns a guard if the caller is now in charge of generating the
+    // thumbnail, or None if it was already in flight, in which case
+    // `outcome_tx` receives the outcome once generation completes.
+    fn claim(
+        &self,
+        uri: &str,
+        flavor: ThumbFlavor,
+        outcome_tx: &mpsc::Sender<Outcome>,
+    ) -> Option<InFlightGuard<'_>> {
+        match self.0.lock().unwrap().entry((canonical_uri(uri), flavor)) {
+            Entry::Occupied(mut waiters) => {
+                waiters.get_mut().push((outcome_tx.clone(), uri.to_owned()));
+                None
+            }
+            Entry::Vacant(entry) => {
+                let key = entry.key().clone();
+                entry.insert(Vec::new());
+                Some(InFlightGuard {
+                    in_flight: self,
+                    key: Some(key),
+                })
+            }
+        }
+    }
+}
+
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    // Taken on release, so that a later claim for the same thumbnail is never
+    // released by this guard.
+    key: Option<InFlightKey>,
+}
+
+impl InFlightGuard<'_> {
+    // The key is removed before notifying, so that later claims for the same
+    // thumbnail start over instead of waiting on an already sent outcome.
+    fn release(&mut self) -> Vec<InFlightWaiter> {
+        let Some(key) = self.key.take() else {
+            return Vec::new();
+        };
+        let waiters = self.in_flight.0.lock().unwrap().remove(&key);
+        waiters.unwrap_or_default()
+    }
+
+    fn complete(mut self, outcome: &Outcome) {
+        for (waiter, uri) in self.release() {
+            _ = waiter.blocking_send(outcome.with_uri(uri));
+        }
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    // Only has waiters left if generation was interrupted before completing.
+    fn drop(&mut self) {
+        for (waiter, uri) in self.release() {
+            _ = waiter.blocking_send(Outcome::Error {
+                uri,
+                code: ErrorCode::Failed,
+                message: "thumbnail generation was interrupted".to_owned(),
+            });
+        }
+    }
+}
+
+// Tripped when the cache's file system turns out to be full or read-only, after
+// which medias fail right away for a while rather than being decoded for
+// nothing.
+struct CacheBackoff {
+    cooldown: Duration,
+    until: Mutex<Option<Instant>>,
+}
+
+impl CacheBackoff {
+    fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            until: Mutex::new(None),
+        }
+    }
+
+    fn trip(&self, message: &str) {
+        if self.cooldown.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut until = self.until.lock().unwrap();
+        if until.is_none_or(|until| until <= now) {
+            warn!(
+                "{message}; failing medias without trying for {:?}",
+                self.cooldown
+            );
+        }
+        *until = Some(now + self.cooldown);
+    }
 
-fn process_item(
+    fn remaining(&self) -> Option<Duration> {
+        let until = (*self.until.lock().unwrap())?;
+        Some(until.checked_duration_since(Instant::now())?).filter(|left| !left.is_zero())
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
+
+type ItemResult = std::thread::Result<Result<(PathBuf, ThumbSource), ThumbError>>;
+
+// Decoders may panic on malformed input, which must only fail this media.
+fn run_item(
     id: usize,
     cache_dir: &Path,
     flavor: &ThumbFlavor,
+    options: &Options,
     media: &MediaRef,
-) -> anyhow::Result<()> {
-    let original_path = match url::Url::parse(&media.uri)?.to_file_path() {
-        Ok(path) => path,
-        Err(_) => return Err(anyhow!("not a file://")),
-    };
-    let cache_dir = flavor.cache_path(cache_dir);
-    let original_meta = ThumbFsMeta::from(&media.uri, &original_path)?;
-    let thumb_path = destination_filename(&cache_dir, &media.uri);
-    // Bail cheaply if already on disk & no changes.
-    if let Ok(existing_original_meta) = get_thumb_original_metadata(&thumb_path) {
-        if existing_original_meta == original_meta {
-            debug!("cache hit for {}", &media.uri);
-            return Ok(());
+) -> ItemResult {
+    std::panic::catch_unwind(AssertUnwindSafe(|| {
+        process_item(id, cache_dir, flavor, options, media)
+    }))
+}
+
+// None if the media took longer than `timeout`. Threads cannot be killed, so
+// the media gets its own, which is left to finish in the background: a stuck
+// decoder keeps its memory and CPU time, but no longer holds up the request.
+fn run_item_with_timeout(
+    id: usize,
+    cache_dir: &Path,
+    flavor: &ThumbFlavor,
+    options: &Options,
+    media: &MediaRef,
+    timeout: Duration,
+) -> Option<ItemResult> {
+    let (result_tx, result_rx) = std::sync::mpsc::channel();
+    let spawned = std::thread::Builder::new()
+        .name("rthumbd-media".to_owned())
+        .spawn({
+            let (cache_dir, flavor, options, media) = (
+                cache_dir.to_owned(),
+                flavor.clone(),
+                *options,
+                media.clone(),
+            );
+            move || {
+                let _ = result_tx.send(run_item(id, &cache_dir, &flavor, &options, &media));
+            }
+        });
+    if let Err(err) = spawned {
+        warn!("cannot spawn a thread, thumbnailing without a timeout: {err}");
+        return Some(run_item(id, cache_dir, flavor, options, media));
+    }
+    match result_rx.recv_timeout(timeout) {
+        Ok(result) => Some(result),
+        Err(RecvTimeoutError::Timeout) => None,
+        Err(RecvTimeoutError::Disconnected) => Some(Err(Box::new("thumbnailing thread died"))),
+    }
+}
+
+const OUTCOME_CHANNEL_CAPACITY: usize = 64;
+// Chunks each thread of the pool gets per slice of a request.
+const SLICE_CHUNKS_PER_THREAD: usize = 4;
+const READY_FLUSH_LEN: usize = 10;
+const READY_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+// Eviction scans the whole cache, so it runs at most this often however many
+// requests finish meanwhile.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(30);
+
+// Outcomes of a request's medias, logged once it finishes.
+#[derive(Default)]
+struct RequestStats {
+    reused: usize,
+    generated: usize,
+    failed: usize,
+}
+
+// Coalesces successes into Ready signals of a few URIs each, so thumbnails show
+// up progressively without emitting one signal per file.
+async fn send_results(
+    handle: u32,
+    mut outcome_rx: mpsc::Receiver<Outcome>,
+    tx: mpsc::Sender<Reply>,
+) -> anyhow::Result<RequestStats> {
+    let mut stats = RequestStats::default();
+    let mut uris = Vec::new();
+    let mut paths = Vec::new();
+    let mut deadline: Option<Instant> = None;
+    loop {
+        tokio::select! {
+            outcome = outcome_rx.recv() => match outcome {
+                Some(Outcome::Ready { uri, path, source }) => {
+                    if source.is_reused() {
+                        stats.reused += 1;
+                    } else {
+                        stats.generated += 1;
+                    }
+                    uris.push(uri);
+                    paths.push(path);
+                    deadline.get_or_insert_with(|| Instant::now() + READY_FLUSH_INTERVAL);
+                    if uris.len() < READY_FLUSH_LEN {
+                        continue;
+                    }
+                }
+                Some(Outcome::Error { uri, code, message }) => {
+                    warn!(handle, uri:% = DisplayUri(&uri), code:?; "error creating thumbnail: {message}");
+                    stats.failed += 1;
+                    tx.send(Reply::Error { handle, uri, code, message }).await?;
+                    continue;
+                }
+                None => break,
+            },
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {}
         }
+        deadline = None;
+        tx.send(Reply::Ready {
+            handle,
+            uris: std::mem::take(&mut uris),
+            paths: std::mem::take(&mut paths),
+        })
+        .await?;
     }
-    let (orig_width, orig_height, thumb) = {
-        let im = image::open(&original_path)?;
-        let dimension = flavor.dimension();
-        (
-            im.width(),
-            im.height(),
-            im.thumbnail(dimension, dimension).to_rgb8(),
-        )
-    };
-    let original_meta = ThumbFullMeta::from(original_meta, orig_width, orig_height);
-    let temp_thumb_path = temp_filename(&cache_dir, &media.uri, id);
-    write_thumb_with_original_metadata(
-        &temp_thumb_path,
-        &original_meta,
-        thumb.width(),
-        thumb.height(),
-        thumb.as_bytes(),
-    )?;
-    std::fs::rename(&temp_thumb_path, &thumb_path)?;
+    if !uris.is_empty() {
+        tx.send(Reply::Ready {
+            handle,
+            uris,
+            paths,
+        })
+        .await?;
+    }
+    Ok(stats)
+}
+
+async fn create_cache_dir_for_flavor(
+    flavor: ThumbFlavor,
+    cache_dir: PathBuf,
+) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        create_private_dir_all(&flavor.cache_path(&cache_dir))?;
+        create_private_dir_all(&fail_dir(&cache_dir, FAIL_APP_NAME))
+    })
+    .await??;
     Ok(())
 }
 
-type Successes<'a> = Vec<&'a MediaRef>;
-type Failures<'a> = Vec<(&'a MediaRef, String)>;
+// Foreground requests are always picked before background ones; within a
+// queue, requests are served in order of arrival.
+#[derive(Default)]
+struct PendingJobs {
+    foreground: VecDeque<ThumbJob>,
+    background: VecDeque<ThumbJob>,
+}
 
-fn process_chunk_concurrently<'a>(
-    cache_dir: &Path,
-    flavor: &ThumbFlavor,
-    chunk: &'a Vec<MediaRef>,
-) -> (Successes<'a>, Failures<'a>) {
-    chunk.par_iter().enumerate().partition_map(|(i, media)| {
-        match process_item(i, cache_dir, flavor, media) {
-            Ok(_) => Left(media),
-            Err(err) => Right((media, err.to_string())),
+impl PendingJobs {
+    fn push(&mut self, job: ThumbJob) {
+        match job.scheduler {
+            Scheduler::Foreground => self.foreground.push_back(job),
+            Scheduler::Background => self.background.push_back(job),
         }
-    })
+    }
+
+    fn pop(&mut self) -> Option<ThumbJob> {
+        self.foreground
+            .pop_front()
+            .or_else(|| self.background.pop_front())
+    }
+
+    async fn next(&mut self, rx: &mut mpsc::Receiver<ThumbJob>) -> Option<ThumbJob> {
+        while let Ok(job) = rx.try_recv() {
+            self.push(job);
+        }
+        match self.pop() {
+            Some(job) => Some(job),
+            None => rx.recv().await,
+        }
+    }
+
+    // Waits until a job is pending, without taking it; false once no job
+    // will ever be.
+    async fn wait(&mut self, rx: &mut mpsc::Receiver<ThumbJob>) -> bool {
+        if self.foreground.is_empty() && self.background.is_empty() {
+            match rx.recv().await {
+                Some(job) => self.push(job),
+                None => return false,
+            }
+        }
+        true
+    }
 }
 
-fn send_results(
-    handle: u32,
-    successes: Successes,
-    failures: Failures,
+// Shared state for processing requests, each in its own task.
+#[derive(Clone)]
+struct Processor {
+    cache_dir: PathBuf,
+    options: Options,
+    chunk_size: usize,
+    pool: Arc<rayon::ThreadPool>,
+    background_pool: Arc<rayon::ThreadPool>,
+    media_timeout: Option<Duration>,
+    isolate_decoders: bool,
+    cache_backoff: Arc<CacheBackoff>,
+    in_flight: Arc<InFlight>,
+    permits: Arc<Semaphore>,
     tx: mpsc::Sender<Reply>,
-) -> anyhow::Result<()> {
-    tx.blocking_send(Reply::Ready {
-        handle,
-        uris: successes
-            .into_iter()
-            .map(|media| media.uri.clone())
-            .collect(),
-    })?;
-    for (media, message) in failures {
-        warn!("error creating thumbnail for {}: {}", &media.uri, &message);
-        tx.blocking_send(Reply::Error {
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+// Medias of a slice that were not processed.
+#[derive(Default)]
+struct Skipped {
+    cancelled: usize,
+    backed_off: usize,
+}
+
+impl Processor {
+    // Medias are handed to the pool in request order, at most `chunk_size`
+    // at once to a given thread, leaving the rest to work stealing.
+    fn process_medias(
+        &self,
+        handle: u32,
+        flavor: &ThumbFlavor,
+        cancel: &CancelToken,
+        medias: Vec<(usize, MediaRef)>,
+        outcome_tx: mpsc::Sender<Outcome>,
+    ) -> anyhow::Result<Skipped> {
+        let skipped = AtomicUsize::new(0);
+        let backed_off = AtomicUsize::new(0);
+        medias
+            .into_par_iter()
+            .with_max_len(self.chunk_size)
+            .filter(|_| {
+                let cancelled = cancel.is_cancelled();
+                if cancelled {
+                    skipped.fetch_add(1, Ordering::Relaxed);
+                }
+                !cancelled
+            })
+            .try_for_each(|(i, media)| {
+                let Some(guard) = self
+                    .in_flight
+                    .claim(&media.uri, flavor.clone(), &outcome_tx)
+                else {
+                    debug!(handle, uri:% = DisplayUri(&media.uri); "already being processed");
+                    return Ok(());
+                };
+                let start = Instant::now();
+                let outcome = if let Some(left) = self.cache_backoff.remaining() {
+                    backed_off.fetch_add(1, Ordering::Relaxed);
+                    STATS.record_failure();
+                    Outcome::Error {
+                        uri: media.uri,
+                        code: ErrorCode::SaveFailed,
+                        message: format!(
+                            "the cache cannot be written to, not trying again for {}s",
+                            left.as_secs().max(1)
+                        ),
+                    }
+                } else if self.isolate_decoders {
+                    worker::run(i, flavor, media, self.media_timeout, &self.cache_backoff)
+                } else {
+                    self.process_media(i, flavor, media)
+                };
+                if let Outcome::Ready { uri, source, .. } = &outcome {
+                    debug!(
+                        handle,
+                        uri:% = DisplayUri(uri),
+                        source:?,
+                        elapsed:? = start.elapsed();
+                        "thumbnail ready"
+                    );
+                    #[cfg(feature = "watch")]
+                    if let Some(watcher) = watcher::get() {
+                        watcher.watch_uri(uri);
+                    }
+                }
+                guard.complete(&outcome);
+                outcome_tx.blocking_send(outcome)
+            })?;
+        Ok(Skipped {
+            cancelled: skipped.into_inner(),
+            backed_off: backed_off.into_inner(),
+        })
+    }
+
+    fn process_media(&self, id: usize, flavor: &ThumbFlavor, media: MediaRef) -> Outcome {
+        let result = match self.media_timeout {
+            Some(timeout) => {
+                run_item_with_timeout(id, &self.cache_dir, flavor, &self.options, &media, timeout)
+            }
+            None => Some(run_item(id, &self.cache_dir, flavor, &self.options, &media)),
+        };
+        match result {
+            Some(Ok(Ok((path, source)))) => Outcome::Ready {
+                uri: media.uri,
+                path,
+                source,
+            },
+            Some(Ok(Err(err))) => {
+                if let ThumbError::CacheUnwritable(_) = err {
+                    self.cache_backoff.trip(&format!("{err:#}"));
+                }
+                Outcome::Error {
+                    uri: media.uri,
+                    code: err.code(),
+                    message: format!("{err:#}"),
+                }
+            }
+            Some(Err(payload)) => Outcome::Error {
+                uri: media.uri,
+                code: ErrorCode::Failed,
+                message: format!("thumbnailer panicked: {}", panic_message(&*payload)),
+            },
+            None => Outcome::Error {
+                uri: media.uri,
+                code: ErrorCode::Failed,
+                message: format!(
+                    "thumbnailing timed out after {:?}",
+                    self.media_timeout.unwrap_or_default()
+                ),
+            },
+        }
+    }
+
+    // Requests are processed a slice at a time, each needing a permit of its
+    // own: as permits go to whoever waited longest, requests take turns
+    // rather than the largest holding up the others until it is done.
+    async fn run(self, req: ThumbJob, permit: OwnedSemaphorePermit) -> anyhow::Result<()> {
+        let handle = req.handle;
+        if req.cancel.is_cancelled() {
+            info!(
+                handle,
+                client:% = req.client,
+                n_uris = req.medias.len();
+                "skipping dequeued thumbnail request"
+            );
+            STATS.record_cancelled(req.medias.len() as u64);
+            self.tx.send(Reply::Finished { handle }).await?;
+            return Ok(());
+        }
+        info!(
             handle,
-            uri: media.uri.clone(),
-            message,
-        })?;
+            client:% = req.client,
+            flavor:% = req.flavor,
+            scheduler:? = req.scheduler,
+            n_uris = req.medias.len();
+            "new thumbnail request"
+        );
+        let start = Instant::now();
+        self.tx.send(Reply::Started { handle }).await?;
+        if let Err(err) =
+            create_cache_dir_for_flavor(req.flavor.clone(), self.cache_dir.clone()).await
+        {
+            let message = format!("cannot create {} cache directory: {err:#}", req.flavor);
+            warn!(handle; "{message}");
+            for media in req.medias {
+                self.tx
+                    .send(Reply::Error {
+                        handle,
+                        uri: media.uri,
+                        code: ErrorCode::SaveFailed,
+                        message: message.clone(),
+                    })
+                    .await?;
+            }
+            self.tx.send(Reply::Finished { handle }).await?;
+            return Ok(());
+        }
+        // Stops the request before its next media on shutdown.
+        let cancel_on_shutdown = {
+            let cancel = req.cancel.clone();
+            let mut shutdown_rx = self.shutdown_rx.clone();
+            tokio::spawn(async move {
+                if shutdown_rx.wait_for(|&stop| stop).await.is_ok() {
+                    cancel.cancel();
+                }
+            })
+        };
+        let (outcome_tx, outcome_rx) = mpsc::channel(OUTCOME_CHANNEL_CAPACITY);
+        let reporter = tokio::spawn(send_results(handle, outcome_rx, self.tx.clone()));
+        let pool = match req.scheduler {
+            Scheduler::Foreground => self.pool.clone(),
+            Scheduler::Background => self.background_pool.clone(),
+        };
+        let slice_len = self.chunk_size * pool.current_num_threads() * SLICE_CHUNKS_PER_THREAD;
+        let mut medias = req.medias.into_iter().enumerate();
+        let mut permit = Some(permit);
+        let mut skipped = Skipped::default();
+        loop {
+            let slice: Vec<_> = medias.by_ref().take(slice_len).collect();
+            if slice.is_empty() {
+                break;
+            }
+            if req.cancel.is_cancelled() {
+                skipped.cancelled += slice.len() + medias.len();
+                break;
+            }
+            let permit = match permit.take() {
+                Some(permit) => permit,
+                None => self.permits.clone().acquire_owned().await?,
+            };
+            let worker = {
+                let processor = self.clone();
+                let (pool, cancel, flavor) = (pool.clone(), req.cancel.clone(), req.flavor.clone());
+                let outcome_tx = outcome_tx.clone();
+                tokio::task::spawn_blocking(move || {
+                    pool.install(|| {
+                        processor.process_medias(handle, &flavor, &cancel, slice, outcome_tx)
+                    })
+                })
+            };
+            let slice_skipped = worker.await??;
+            drop(permit);
+            skipped.cancelled += slice_skipped.cancelled;
+            skipped.backed_off += slice_skipped.backed_off;
+        }
+        drop(outcome_tx);
+        STATS.record_cancelled(skipped.cancelled as u64);
+        if skipped.cancelled > 0 {
+            info!(
+                handle,
+                client:% = req.client,
+                skipped = skipped.cancelled;
+                "request was cancelled"
+            );
+        }
+        if skipped.backed_off > 0 {
+            warn!(
+                handle,
+                n_uris = skipped.backed_off;
+                "failed medias without trying, as the cache cannot be written to"
+            );
+        }
+        let stats = reporter.await??;
+        info!(
+            handle,
+            client:% = req.client,
+            reused = stats.reused,
+            generated = stats.generated,
+            failed = stats.failed,
+            elapsed:? = start.elapsed();
+            "request finished"
+        );
+        cancel_on_shutdown.abort();
+        self.tx.send(Reply::Finished { handle }).await?;
+        Ok(())
     }
-    Ok(())
 }
 
-fn process_chunk_and_reply(
+fn thumbnail_to(input: &str, output: &Path, size: u32, options: &Options) -> anyhow::Result<()> {
+    let original_path = match url::Url::parse(input) {
+        Ok(uri) => uri
+            .to_file_path()
+            .map_err(|_| anyhow!("not a file:// URI: {input}"))?,
+        Err(_) => std::path::absolute(input)?,
+    };
+    let uri = url::Url::from_file_path(&original_path)
+        .map_err(|_| anyhow!("cannot build a file:// URI for {input}"))?;
+    let media = MediaRef {
+        uri: uri.to_string(),
+        mime_type: String::new(),
+    };
+    let original_meta = ThumbFsMeta::from(&media.uri, &original_path)?;
+    let (original_meta, thumb) =
+        render_thumbnail(&original_path, original_meta, &media, options, size)?;
+    write_image(output, &original_meta, &thumb, &options.write)
+}
+
+// Prints the thumbnail path or the error for each file, and fails if any did.
+fn run_once(
     cache_dir: &Path,
-    handle: u32,
     flavor: &ThumbFlavor,
-    chunk: Vec<MediaRef>,
-    tx: mpsc::Sender<Reply>,
+    options: &Options,
+    files: Vec<PathBuf>,
 ) -> anyhow::Result<()> {
-    let (successes, failures) = process_chunk_concurrently(cache_dir, flavor, &chunk);
-    send_results(handle, successes, failures, tx)
+    let results: Vec<_> = files
+        .into_par_iter()
+        .map(|file| {
+            let result = thumbnail_file(cache_dir, &file, flavor, options);
+            (file, result)
+        })
+        .collect();
+    let mut failed = 0;
+    for (file, result) in results {
+        match result {
+            Ok(path) => println!("{}\t{}", file.display(), path.display()),
+            Err(err) => {
+                failed += 1;
+                println!("{}\terror: {err:#}", file.display());
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{failed} file(s) could not be thumbnailed"));
+    }
+    Ok(())
 }
 
-async fn create_cache_dir_for_flavor(
-    flavor: ThumbFlavor,
-    cache_dir: PathBuf,
-) -> anyhow::Result<()> {
-    tokio::task::spawn_blocking(move || std::fs::create_dir_all(flavor.cache_path(&cache_dir)))
-        .await??;
-    Ok(())
+// Makes the calling thread yield CPU time, and I/O time too unless
+// `niceness` is 0. Both are per thread on Linux, and inherited by the threads
+// and processes it starts. Lowering one's own priority cannot fail.
+fn lower_priority(niceness: i32) {
+    if niceness == 0 {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    unsafe {
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        libc::nice(niceness);
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        );
+    }
+}
+
+// Failures here are about the daemon itself, such as the reply channel being
+// closed, not about thumbnailing: those are reported to clients instead.
+fn log_request_failure(res: Result<anyhow::Result<()>, tokio::task::JoinError>) {
+    match res {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => warn!("request failed: {err:#}"),
+        Err(err) => warn!("request task failed: {err}"),
+    }
+}
+
+// Requests shutdown on the first SIGTERM or SIGINT, then exits right away if
+// draining takes longer than `drain_timeout` or another signal comes in.
+async fn handle_signals(
+    mut sigterm: Signal,
+    mut sigint: Signal,
+    shutdown_tx: watch::Sender<bool>,
+    drain_timeout: Duration,
+) {
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = sigint.recv() => {}
+    }
+    info!("shutting down, draining requests for at most {drain_timeout:?}");
+    _ = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]);
+    _ = shutdown_tx.send(true);
+    tokio::select! {
+        _ = tokio::time::sleep(drain_timeout) => warn!("drain timeout expired, exiting"),
+        _ = sigterm.recv() => warn!("signaled again, exiting without draining"),
+        _ = sigint.recv() => warn!("signaled again, exiting without draining"),
+    }
+    std::process::exit(1);
+}
+
+async fn log_stats(mut sigusr1: Signal, reply_tx: mpsc::WeakSender<Reply>) {
+    while sigusr1.recv().await.is_some() {
+        info!("statistics: {}", STATS.snapshot());
+        // Through the forwarder, which knows the clients of live requests.
+        if let Some(reply_tx) = reply_tx.upgrade() {
+            _ = reply_tx.send(Reply::LogClients).await;
+        }
+    }
+}
+
+// Notifies the systemd watchdog at half its interval, for as long as both the
+// main loop and the reply forwarder answer heartbeats in time.
+async fn watchdog(
+    interval: Duration,
+    loop_tx: mpsc::Sender<oneshot::Sender<()>>,
+    reply_tx: mpsc::WeakSender<Reply>,
+) {
+    let mut ticker = tokio::time::interval(interval / 2);
+    loop {
+        ticker.tick().await;
+        let heartbeat = async {
+            let (loop_ack, loop_acked) = oneshot::channel();
+            let (reply_ack, reply_acked) = oneshot::channel();
+            loop_tx.send(loop_ack).await.ok()?;
+            reply_tx
+                .upgrade()?
+                .send(Reply::Heartbeat(reply_ack))
+                .await
+                .ok()?;
+            loop_acked.await.ok()?;
+            reply_acked.await.ok()
+        };
+        if tokio::time::timeout(interval / 2, heartbeat)
+            .await
+            .ok()
+            .flatten()
+            .is_none()
+        {
+            // The main loop drops its end when shutting down.
+            if !loop_tx.is_closed() {
+                warn!("daemon is unresponsive, no longer notifying the watchdog");
+            }
+            return;
+        }
+        _ = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]);
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init();
+    let mut args = Args::parse();
+    let mut logger = env_logger::Builder::from_default_env();
+    match args.verbose {
+        0 => {}
+        1 => _ = logger.filter_level(log::LevelFilter::Debug),
+        _ => _ = logger.filter_level(log::LevelFilter::Trace),
+    }
+    logger.init();
+
+    let mut config = Config::load(args.config.as_deref())?;
+    let command = args.command.take();
+    let check_config = args.check_config;
+    args.apply(&mut config);
+    redact::set_enabled(config.redact_uris);
 
-    let chunk_size: usize = std::env::var("RTHUMB_CHUNK_SIZE")
-        .unwrap_or_default()
-        .parse()
-        .unwrap_or(2);
+    match command {
+        Some(Command::Install { systemd, force }) => {
+            if config.bus == BusType::System {
+                return Err(anyhow!("install only supports the session bus"));
+            }
+            return install::install(&config.bus_name, systemd, force);
+        }
+        Some(Command::Uninstall) => return install::uninstall(&config.bus_name),
+        Some(Command::Thumbnail {
+            input,
+            output,
+            size,
+        }) => {
+            let options = Options {
+                mime_sniffing: config.mime_sniffing,
+                write: config.write_options(),
+                ..Default::default()
+            };
+            return thumbnail_to(&input, &output, size, &options);
+        }
+        Some(Command::Once { .. } | Command::Cleanup { .. } | Command::Worker) | None => {}
+    }
 
-    let cache_dir = cache_destination()?;
+    if config.cache_dir.is_none() {
+        config.cache_dir = Some(cache_destination()?);
+    }
+    let flavors = config.flavor_set()?;
+    if check_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
+    // Dedicated rather than global, so that thumbnailing threads are easy to
+    // tell apart, e.g. in top or a debugger.
+    let build_pool = || {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(config.threads.map_or(0, NonZeroUsize::get))
+            .thread_name(|i| format!("rthumbd-worker-{i}"))
+            .build()
+    };
+    let chunk_size = config.chunk_size.get();
+    let options = Options {
+        mime_sniffing: config.mime_sniffing,
+        legacy_cache_fallback: config.legacy_cache_fallback,
+        shared_repositories: config.shared_repositories,
+        strict_validation: config.strict_validation,
+        max_source_bytes: config.max_source_bytes(),
+        validation_cache_size: config.validation_cache_size,
+        write: config.write_options(),
+    };
+    let cache_dir = config.cache_dir.clone().unwrap_or_default();
+    if let Some(Command::Worker) = command {
+        if config.exec_thumbnailers != ExecMode::Off {
+            exec::init(config.exec_thumbnailers());
+        }
+        return worker::serve(&cache_dir, &flavors, &options);
+    }
     info!("using chunk size: {chunk_size:?}");
+    info!("using options: {options:?}");
     info!("using cache directory: {cache_dir:?}");
+    info!("using flavors: {}", flavors.iter().join(", "));
+    if config.exec_thumbnailers != ExecMode::Off {
+        let thumbnailers = config.exec_thumbnailers();
+        info!(
+            "using {} external thumbnailer(s), {:?}",
+            thumbnailers.iter().count(),
+            config.exec_thumbnailers
+        );
+        for thumbnailer in thumbnailers.iter() {
+            debug!(
+                "{} thumbnailer for {}",
+                thumbnailer.name,
+                thumbnailer.mime_types.join(", ")
+            );
+        }
+        exec::init(thumbnailers);
+    }
+    for (mime_type, decoders) in decoders_by_mime_type() {
+        debug!("decoders for {mime_type}: {}", decoders.join(", "));
+    }
+
+    match command {
+        Some(Command::Once { flavor, files }) => {
+            let flavor = flavors.get(&flavor).ok_or_else(|| {
+                anyhow!(
+                    "unknown flavor '{flavor}', expected one of: {}",
+                    flavors.iter().join(", ")
+                )
+            })?;
+            return build_pool()?.install(|| run_once(&cache_dir, flavor, &options, files));
+        }
+        Some(Command::Cleanup { only_ours }) => {
+            let stats = cache::cleanup_temp_files(&cache_dir, &flavors)?;
+            println!("temporary files: {stats}");
+            let options = CleanupOptions {
+                only_ours,
+                ..config.cleanup_options(&flavors)
+            };
+            let stats = cache::cleanup(&cache_dir, &options)?;
+            println!("{stats}");
+            if let Some(quota) = config.cache_quota_bytes() {
+                let stats = cache::evict(&cache_dir, &flavors, quota, SystemTime::now())?;
+                println!("evicted: {stats}");
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
 
-    let (mut rx, tx) = dbus::Thumbnailer1::create_and_listen().await?;
+    if config.migrate_legacy_cache {
+        let legacy_dir = legacy_cache_destination()?;
+        match cache::migrate_legacy_cache(&legacy_dir, &cache_dir) {
+            Ok(0) => {}
+            Ok(moved) => info!("moved {moved} file(s) from {legacy_dir:?} into the cache"),
+            Err(err) => warn!("cannot migrate {legacy_dir:?}: {err:#}"),
+        }
+    }
+    if let Err(err) = cache::check_permissions(&cache_dir, &flavors, config.fix_permissions) {
+        warn!("cannot check the permissions of {cache_dir:?}: {err:#}");
+    }
+    match cache::cleanup_temp_files(&cache_dir, &flavors) {
+        Ok(stats) if stats.removed > 0 => info!("leftover temporary files: {stats}"),
+        Ok(_) => {}
+        Err(err) => warn!("cannot remove leftover temporary files: {err:#}"),
+    }
+
+    // Landlock only restricts threads started from now on, so this must come
+    // before the pool's.
+    if config.sandbox != SandboxMode::Off {
+        create_private_dir_all(&cache_dir)?;
+        let mut writable = vec![cache_dir.clone(), PathBuf::from("/dev/null")];
+        if config.exec_thumbnailers != ExecMode::Off {
+            writable.push(std::env::temp_dir());
+        }
+        if config.shared_repositories {
+            warn!("shared repositories cannot be written to in the sandbox");
+        }
+        let options = SandboxOptions {
+            writable,
+            allow_inet: config.metrics_address.is_some(),
+        };
+        match sandbox::apply(config.sandbox, &options)? {
+            level if level == SandboxLevel::default() => {
+                warn!("this kernel does not support sandboxing, running unrestricted")
+            }
+            level => info!("sandbox: {level}"),
+        }
+    }
+    let pool = Arc::new(build_pool()?);
+    let background_niceness = config.background_niceness;
+    let background_pool = Arc::new(
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(config.background_threads.map_or_else(
+                || (pool.current_num_threads() / 2).max(1),
+                NonZeroUsize::get,
+            ))
+            .thread_name(|i| format!("rthumbd-background-{i}"))
+            .start_handler(move |_| lower_priority(background_niceness))
+            .build()?,
+    );
+    info!(
+        "using {} thread(s), and {} for background requests",
+        pool.current_num_threads(),
+        background_pool.current_num_threads()
+    );
+    if config.isolate_decoders {
+        info!(
+            "decoding in up to {} worker process(es)",
+            pool.current_num_threads() + background_pool.current_num_threads()
+        );
+    }
+
+    let listen_options = ListenOptions {
+        bus: config.bus,
+        name: config.bus_name.clone(),
+        flavors: flavors.clone(),
+        max_uris: config.max_queue_uris(),
+        split_requests: config.split_large_queues,
+        ..Default::default()
+    };
+    let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+    tokio::spawn(handle_signals(
+        signal(SignalKind::terminate())?,
+        signal(SignalKind::interrupt())?,
+        shutdown_tx,
+        Duration::from_secs(config.drain_timeout),
+    ));
+    let sigusr1 = signal(SignalKind::user_defined1())?;
+
+    let (mut rx, tx, forwarder, live_handles) =
+        dbus::Thumbnailer1::create_and_listen(&listen_options).await?;
+    tokio::spawn(log_stats(sigusr1, tx.downgrade()));
 
     _ = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]);
-    info!("successfully installed DBus service");
+    info!(
+        "successfully installed DBus service as {} on the {} bus",
+        listen_options.name, listen_options.bus
+    );
 
-    while let Some(req) = rx.recv().await {
-        info!("new thumbnail request: {req:?}");
-        create_cache_dir_for_flavor(req.flavor, cache_dir.clone()).await?;
-        let handle = req.handle;
-        let mut handles: Vec<_> = Vec::new();
-        for chunk in &req.medias.into_iter().rev().chunks(chunk_size) {
-            let cache_dir = cache_dir.clone();
-            let tx = tx.clone();
-            let chunk: Vec<_> = chunk.collect();
-            handles.push(tokio::task::spawn_blocking(move || {
-                process_chunk_and_reply(&cache_dir, handle, &req.flavor, chunk, tx)
-            }));
-        }
-        for h in handles {
-            h.await??;
+    // Requests share the worker pool, so running several of them at
+    // once does not add CPU threads, it only keeps the pool busy. Permits
+    // are taken again for each slice of a request, see Processor::run.
+    let max_requests = config.max_requests.map_or_else(
+        || std::thread::available_parallelism().map_or(1, |n| (n.get() / chunk_size).max(1)),
+        NonZeroUsize::get,
+    );
+    info!("processing at most {max_requests} request(s) at once");
+    let permits = Arc::new(Semaphore::new(max_requests));
+    if let Some(address) = config.metrics_address {
+        #[cfg(feature = "metrics")]
+        tokio::spawn({
+            let permits = permits.clone();
+            async move {
+                if let Err(err) = metrics::serve(address, permits, max_requests).await {
+                    warn!("cannot serve metrics on {address}: {err:#}");
+                }
+            }
+        });
+        #[cfg(not(feature = "metrics"))]
+        warn!("not serving metrics on {address}: built without the metrics feature");
+    }
+    if config.watch_dirs > 0 {
+        #[cfg(feature = "watch")]
+        match watcher::init(cache_dir.clone(), flavors.clone(), config.watch_dirs) {
+            Ok(()) => info!(
+                "watching up to {} directory(ies) for changes",
+                config.watch_dirs
+            ),
+            Err(err) => warn!("cannot watch for changes: {err:#}"),
         }
-        tx.send(Reply::Finished { handle }).await?;
+        #[cfg(not(feature = "watch"))]
+        warn!("not watching for changes: built without the watch feature");
+    }
+    let cleanup_interval = Duration::from_secs(config.cleanup_interval);
+    let cleanup_options = config.cleanup_options(&flavors);
+    let cache_quota = config.cache_quota_bytes();
+    let mut next_cleanup = Instant::now()
+        + cleanup_interval
+            .saturating_sub(cache::since_last_cleanup(&cache_dir).unwrap_or(cleanup_interval));
+    let mut next_eviction = Instant::now();
+    let mut eviction_pending = false;
+    // Cleanup and eviction both remove files, one at a time.
+    let maintenance = Arc::new(Mutex::new(()));
+    let processor = Processor {
+        cache_dir: cache_dir.clone(),
+        options,
+        chunk_size,
+        pool,
+        background_pool,
+        media_timeout: config.media_timeout(),
+        isolate_decoders: config.isolate_decoders,
+        cache_backoff: Arc::new(CacheBackoff::new(config.cache_full_cooldown())),
+        in_flight: Arc::new(InFlight::default()),
+        permits: permits.clone(),
+        tx: tx.clone(),
+        shutdown_rx: shutdown_rx.clone(),
+    };
+    let (heartbeat_tx, mut heartbeat_rx) = mpsc::channel::<oneshot::Sender<()>>(1);
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        let interval = Duration::from_micros(usec);
+        info!("notifying the systemd watchdog every {:?}", interval / 2);
+        tokio::spawn(watchdog(interval, heartbeat_tx, tx.downgrade()));
+    }
+    let idle_timeout = Duration::from_secs(config.idle_timeout);
+    let mut requests = JoinSet::new();
+    let mut disconnected = false;
+    let mut last_activity = Instant::now();
+    let mut pending = PendingJobs::default();
+    loop {
+        // Only picks the next request once it can start, so that foreground
+        // requests queued meanwhile still go first. Permits are only taken
+        // once there is a request, as running ones need them back for their
+        // next slice.
+        let next = async {
+            if !pending.wait(&mut rx).await {
+                return anyhow::Ok(None);
+            }
+            let permit = permits.clone().acquire_owned().await?;
+            anyhow::Ok(pending.next(&mut rx).await.map(|req| (permit, req)))
+        };
+        let next = tokio::select! {
+            biased;
+            Ok(_) = shutdown_rx.wait_for(|&stop| stop) => break,
+            Some(res) = requests.join_next() => {
+                log_request_failure(res);
+                last_activity = Instant::now();
+                eviction_pending = cache_quota.is_some();
+                continue;
+            }
+            Some(ack) = heartbeat_rx.recv() => {
+                _ = ack.send(());
+                continue;
+            }
+            next = next => next?,
+            // Only counts while no request is being processed.
+            _ = tokio::time::sleep_until(last_activity + idle_timeout), if !idle_timeout.is_zero() && requests.is_empty() => {
+                // Specialized thumbnailers may still be working on some
+                // handles, whose signals are relayed until they finish.
+                if !live_handles.is_empty() {
+                    last_activity = Instant::now();
+                    continue;
+                }
+                info!("idle for {idle_timeout:?}, exiting");
+                _ = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]);
+                break;
+            }
+            _ = tokio::time::sleep_until(next_eviction), if eviction_pending && requests.is_empty() => {
+                eviction_pending = false;
+                next_eviction = Instant::now() + EVICTION_INTERVAL;
+                let Some(quota) = cache_quota else {
+                    continue;
+                };
+                let (cache_dir, flavors) = (cache_dir.clone(), flavors.clone());
+                let maintenance = maintenance.clone();
+                let since = SystemTime::now();
+                tokio::task::spawn_blocking(move || {
+                    let _maintenance = maintenance.lock().unwrap();
+                    match cache::evict(&cache_dir, &flavors, quota, since) {
+                        Ok(stats) if stats.removed > 0 => info!("cache over quota: {stats}"),
+                        Ok(_) => {}
+                        Err(err) => warn!("cache eviction failed: {err:#}"),
+                    }
+                });
+                continue;
+            }
+            _ = tokio::time::sleep_until(next_cleanup), if !cleanup_interval.is_zero() && requests.is_empty() => {
+                next_cleanup = Instant::now() + cleanup_interval;
+                let (cache_dir, options) = (cache_dir.clone(), cleanup_options.clone());
+                let maintenance = maintenance.clone();
+                tokio::task::spawn_blocking(move || {
+                    let _maintenance = maintenance.lock().unwrap();
+                    match cache::cleanup(&cache_dir, &options) {
+                        Ok(stats) => info!("cache cleanup: {stats}"),
+                        Err(err) => warn!("cache cleanup failed: {err:#}"),
+                    }
+                });
+                continue;
+            }
+        };
+        let Some((permit, req)) = next else {
+            _ = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]);
+            disconnected = true;
+            break;
+        };
+        last_activity = Instant::now();
+        let processor = processor.clone();
+        requests.spawn(processor.run(req, permit));
     }
 
-    std::panic!()
+    drop(heartbeat_rx);
+    // Further Queue calls fail from now on, and whatever was still pending
+    // is reported as finished without being processed.
+    rx.close();
+    while let Some(req) = pending.next(&mut rx).await {
+        info!(
+            handle = req.handle,
+            client:% = req.client,
+            n_uris = req.medias.len();
+            "abandoning thumbnail request"
+        );
+        tx.send(Reply::Finished { handle: req.handle }).await?;
+    }
+    while let Some(res) = requests.join_next().await {
+        log_request_failure(res);
+    }
+    drop(processor);
+    drop(tx);
+    forwarder.await?;
+    if disconnected {
+        // Lets the service manager restart the daemon.
+        return Err(anyhow!("D-Bus connection lost"));
+    }
+    info!("session statistics: {}", STATS.snapshot());
+    info!("shut down cleanly");
+    Ok(())
 }
@@ -0,0 +1,136 @@
+use std::{fmt::Write, net::SocketAddr, sync::Arc};
+
+use log::{debug, info};
+use rthumbd::stats::{DURATION_BUCKETS, STATS};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+
+// Request heads larger than this are not worth reading.
+const MAX_REQUEST_LEN: usize = 8192;
+
+// Serves GET /metrics in the Prometheus text format, with no label that could
+// reveal a file name.
+pub async fn serve(
+    address: SocketAddr,
+    permits: Arc<Semaphore>,
+    max_requests: usize,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let running = max_requests.saturating_sub(permits.available_permits());
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, running).await {
+                debug!("metrics request from {peer} failed: {err}");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, running: usize) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_LEN {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let request_line = head.split(|&b| b == b'\r').next().unwrap_or_default();
+    let (status, body) = match request_line.split(|&b| b == b' ').collect::<Vec<_>>()[..] {
+        [b"GET", b"/metrics", _] => ("200 OK", render(running)),
+        [b"GET", ..] => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP rthumbd_{name} {help}");
+    _ = writeln!(out, "# TYPE rthumbd_{name} {kind}");
+}
+
+fn render(running: usize) -> String {
+    let stats = STATS.snapshot();
+    let mut out = String::new();
+    header(
+        &mut out,
+        "thumbnails_total",
+        "counter",
+        "Medias processed, by outcome.",
+    );
+    for (outcome, count) in [
+        ("cache_hit", stats.cache_hits),
+        ("reused", stats.reused),
+        ("generated", stats.generated),
+        ("failed", stats.failed),
+        ("cancelled", stats.cancelled),
+    ] {
+        _ = writeln!(
+            out,
+            "rthumbd_thumbnails_total{{outcome=\"{outcome}\"}} {count}"
+        );
+    }
+    header(
+        &mut out,
+        "generation_seconds",
+        "histogram",
+        "Time spent generating thumbnails.",
+    );
+    let mut cumulative = 0;
+    for (i, count) in stats.generation_buckets.iter().enumerate() {
+        cumulative += count;
+        let bound = DURATION_BUCKETS.get(i).map_or_else(
+            || "+Inf".to_owned(),
+            |bound| bound.as_secs_f64().to_string(),
+        );
+        _ = writeln!(
+            out,
+            "rthumbd_generation_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+        );
+    }
+    _ = writeln!(
+        out,
+        "rthumbd_generation_seconds_sum {}",
+        stats.generation_time.as_secs_f64()
+    );
+    _ = writeln!(out, "rthumbd_generation_seconds_count {cumulative}");
+    header(
+        &mut out,
+        "validations_total",
+        "counter",
+        "Up-to-date checks of cached thumbnails, by whether they had to be read.",
+    );
+    _ = writeln!(
+        out,
+        "rthumbd_validations_total{{read=\"false\"}} {}",
+        stats.validation_hits
+    );
+    _ = writeln!(
+        out,
+        "rthumbd_validations_total{{read=\"true\"}} {}",
+        stats.validation_misses
+    );
+    header(
+        &mut out,
+        "running_requests",
+        "gauge",
+        "Requests being processed.",
+    );
+    _ = writeln!(out, "rthumbd_running_requests {running}");
+    out
+}
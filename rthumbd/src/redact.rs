@@ -0,0 +1,144 @@
+use std::{
+    fmt,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Off by default; the daemon turns it on from its configuration on startup.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Displays a URI as is, or once redaction is enabled, only its scheme, a hash
+// of the whole URI and the extension of its file name, e.g.
+// `file://…/3f2a9c1e.jpg`, so that log lines can still be told apart and
+// correlated without revealing file names.
+pub struct DisplayUri<'a>(pub &'a str);
+
+impl fmt::Display for DisplayUri<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !is_enabled() {
+            return f.write_str(self.0);
+        }
+        let Ok(url) = url::Url::parse(self.0) else {
+            return write_redacted(f, "", self.0, None);
+        };
+        // The last segment stays percent-encoded, any extension found in it
+        // is plain ASCII.
+        let name = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back());
+        write_redacted(f, &format!("{}://…/", url.scheme()), self.0, name)
+    }
+}
+
+// Same as DisplayUri for local paths.
+pub struct DisplayPath<'a>(pub &'a Path);
+
+impl fmt::Display for DisplayPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !is_enabled() {
+            return write!(f, "{}", self.0.display());
+        }
+        let name = self.0.file_name().and_then(|name| name.to_str());
+        write_redacted(f, "…/", &self.0.to_string_lossy(), name)
+    }
+}
+
+fn write_redacted(
+    f: &mut fmt::Formatter<'_>,
+    prefix: &str,
+    whole: &str,
+    name: Option<&str>,
+) -> fmt::Result {
+    let digest = md5::compute(whole);
+    write!(f, "{prefix}<")?;
+    for byte in &digest[..4] {
+        write!(f, "{byte:0>2x}")?;
+    }
+    f.write_str(">")?;
+    match name.and_then(extension) {
+        Some(extension) => write!(f, ".{extension}"),
+        None => Ok(()),
+    }
+}
+
+// Short alphanumeric extensions only: anything else may be part of the name.
+fn extension(name: &str) -> Option<&str> {
+    let (stem, extension) = name.rsplit_once('.')?;
+    (!stem.is_empty()
+        && (1..=5).contains(&extension.len())
+        && extension.bytes().all(|b| b.is_ascii_alphanumeric()))
+    .then_some(extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt, sync::Mutex};
+
+    use super::*;
+
+    // Redaction is process-wide, tests toggling it must not overlap.
+    static ENABLED_LOCK: Mutex<()> = Mutex::new(());
+
+    fn redacted(uri: &str) -> String {
+        let _lock = ENABLED_LOCK.lock().unwrap();
+        set_enabled(true);
+        DisplayUri(uri).to_string()
+    }
+
+    #[test]
+    fn displays_uris_as_is_by_default() {
+        let _lock = ENABLED_LOCK.lock().unwrap();
+        set_enabled(false);
+        let uri = "file:///home/me/a%20b.jpg?x=1";
+        assert_eq!(DisplayUri(uri).to_string(), uri);
+    }
+
+    #[test]
+    fn redacts_the_path() {
+        assert_eq!(
+            redacted("file:///home/me/photo.jpg?size=1#x"),
+            "file://…/<1bd54496>.jpg"
+        );
+    }
+
+    #[test]
+    fn ignores_query_strings() {
+        let redacted = redacted("https://host/view?file=secret.png");
+        assert_eq!(redacted, "https://…/<036c0ce7>");
+        assert!(!redacted.contains("secret"));
+    }
+
+    #[test]
+    fn keeps_extensions_of_non_utf8_names() {
+        assert_eq!(
+            redacted("file:///tmp/%FF%FE.png"),
+            "file://…/<cb44f29f>.png"
+        );
+    }
+
+    #[test]
+    fn drops_escaped_extensions() {
+        assert_eq!(redacted("file:///tmp/a.%FF"), "file://…/<6292f539>");
+    }
+
+    #[test]
+    fn redacts_unparsable_uris_entirely() {
+        assert_eq!(redacted("not a uri.jpg"), "<e1061c99>");
+    }
+
+    #[test]
+    fn redacts_non_utf8_paths() {
+        let _lock = ENABLED_LOCK.lock().unwrap();
+        set_enabled(true);
+        let path = Path::new(OsStr::from_bytes(b"/home/me/r\xe9.png"));
+        assert_eq!(DisplayPath(path).to_string(), "…/<9cf0ad60>");
+    }
+}
@@ -0,0 +1,356 @@
+use std::{fmt, path::PathBuf};
+
+// How much the daemon restricts itself once set up.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SandboxMode {
+    #[default]
+    Off,
+    // Writes only to the cache, through Landlock.
+    Landlock,
+    // Landlock, plus a seccomp filter refusing syscalls decoders never need.
+    Strict,
+}
+
+#[derive(Debug, Default)]
+pub struct SandboxOptions {
+    // Everything else is read-only.
+    pub writable: Vec<PathBuf>,
+    // Let IPv4 and IPv6 sockets be created, e.g. to serve metrics.
+    pub allow_inet: bool,
+}
+
+// What could actually be applied: older kernels lack Landlock or some of its
+// access rights, other architectures get no seccomp filter.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SandboxLevel {
+    pub landlock_abi: Option<i32>,
+    pub seccomp: bool,
+}
+
+impl fmt::Display for SandboxLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.landlock_abi, self.seccomp) {
+            (None, false) => f.write_str("none"),
+            (Some(abi), false) => write!(f, "landlock (ABI {abi})"),
+            (None, true) => f.write_str("seccomp"),
+            (Some(abi), true) => write!(f, "landlock (ABI {abi}) and seccomp"),
+        }
+    }
+}
+
+// Restricts the calling thread and the threads and processes it starts from
+// then on, so this must run before any thread that decodes files is spawned.
+// The seccomp filter also covers existing threads.
+pub fn apply(mode: SandboxMode, options: &SandboxOptions) -> anyhow::Result<SandboxLevel> {
+    if mode == SandboxMode::Off {
+        return Ok(SandboxLevel::default());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        linux::apply(mode, options)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        _ = options;
+        Ok(SandboxLevel::default())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        fs::File,
+        io,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        path::Path,
+    };
+
+    use anyhow::Context;
+    use log::debug;
+
+    use super::{SandboxLevel, SandboxMode, SandboxOptions};
+
+    pub fn apply(mode: SandboxMode, options: &SandboxOptions) -> anyhow::Result<SandboxLevel> {
+        // Required by both, and keeps setuid programs started by external
+        // thumbnailers from escaping them.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error()).context("cannot set no_new_privs");
+        }
+        let landlock_abi = landlock(&options.writable)?;
+        let seccomp = mode == SandboxMode::Strict && seccomp(options.allow_inet)?;
+        Ok(SandboxLevel {
+            landlock_abi,
+            seccomp,
+        })
+    }
+
+    const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+    const LANDLOCK_RULE_PATH_BENEATH: libc::c_uint = 1;
+
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+    const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+    const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+    const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+    const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+    // ABI 2.
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    // ABI 3.
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    // Only those that apply to files can be granted on a file.
+    const FILE_ACCESS: u64 = ACCESS_FS_WRITE_FILE | ACCESS_FS_TRUNCATE;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    // Reading stays allowed everywhere: every write right is handled, and only
+    // granted beneath `writable`. None if the kernel lacks Landlock.
+    fn landlock(writable: &[std::path::PathBuf]) -> anyhow::Result<Option<i32>> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            debug!("landlock unavailable: {}", io::Error::last_os_error());
+            return Ok(None);
+        }
+        let mut handled = ACCESS_FS_WRITE_FILE
+            | ACCESS_FS_REMOVE_DIR
+            | ACCESS_FS_REMOVE_FILE
+            | ACCESS_FS_MAKE_CHAR
+            | ACCESS_FS_MAKE_DIR
+            | ACCESS_FS_MAKE_REG
+            | ACCESS_FS_MAKE_SOCK
+            | ACCESS_FS_MAKE_FIFO
+            | ACCESS_FS_MAKE_BLOCK
+            | ACCESS_FS_MAKE_SYM;
+        if abi >= 2 {
+            handled |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= ACCESS_FS_TRUNCATE;
+        }
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("cannot create a landlock ruleset");
+        }
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+        for path in writable {
+            allow_writes(&ruleset, path, handled)
+                .with_context(|| format!("cannot make {path:?} writable"))?;
+        }
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+            return Err(io::Error::last_os_error()).context("cannot enforce the landlock ruleset");
+        }
+        Ok(Some(abi as i32))
+    }
+
+    fn allow_writes(ruleset: &OwnedFd, path: &Path, handled: u64) -> anyhow::Result<()> {
+        let file = File::open(path)?;
+        let allowed_access = if file.metadata()?.is_dir() {
+            handled
+        } else {
+            handled & FILE_ACCESS
+        };
+        let attr = PathBeneathAttr {
+            allowed_access,
+            parent_fd: file.as_raw_fd(),
+        };
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr,
+                0,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    const AUDIT_ARCH: Option<u32> = None;
+
+    // Syscalls of the x32 ABI have this bit set, and share the x86_64 arch.
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    // Debugging, kernel, namespace and key management syscalls: nothing a
+    // thumbnailer needs, everything an exploit may want.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_keyctl,
+        libc::SYS_acct,
+    ];
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    const DENIED_SYSCALLS: &[libc::c_long] = &[];
+
+    // Offsets in struct seccomp_data; the first argument is read through its
+    // low 32 bits, little-endian on both supported architectures.
+    const DATA_NR: u32 = 0;
+    const DATA_ARCH: u32 = 4;
+    const DATA_ARG0: u32 = 16;
+
+    const BPF_JGE: u32 = 0x30;
+
+    fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    // Denied syscalls fail with EPERM rather than killing the daemon, so that
+    // a decoder trying one only fails the media it was given. False if the
+    // kernel or architecture lacks seccomp.
+    fn seccomp(allow_inet: bool) -> anyhow::Result<bool> {
+        let Some(arch) = AUDIT_ARCH else {
+            debug!("seccomp unavailable on this architecture");
+            return Ok(false);
+        };
+        let deny = |errno: i32| {
+            stmt(
+                libc::BPF_RET | libc::BPF_K,
+                libc::SECCOMP_RET_ERRNO | errno as u32,
+            )
+        };
+        let allow = stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW);
+        let mut families = vec![libc::AF_UNIX as u32];
+        if allow_inet {
+            families.extend([libc::AF_INET as u32, libc::AF_INET6 as u32]);
+        }
+
+        let mut filter = vec![
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_ARCH),
+            jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 1, 0),
+            deny(libc::EPERM),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_NR),
+            jump(libc::BPF_JMP | BPF_JGE | libc::BPF_K, X32_SYSCALL_BIT, 0, 1),
+            deny(libc::EPERM),
+        ];
+        for &nr in DENIED_SYSCALLS {
+            filter.push(jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                nr as u32,
+                0,
+                1,
+            ));
+            filter.push(deny(libc::EPERM));
+        }
+        // Sockets of other families than the allowed ones.
+        filter.push(jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            libc::SYS_socket as u32,
+            1,
+            0,
+        ));
+        filter.push(allow);
+        filter.push(stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_ARG0));
+        for (i, &family) in families.iter().enumerate() {
+            let remaining = (families.len() - i) as u8;
+            filter.push(jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                family,
+                remaining,
+                0,
+            ));
+        }
+        filter.push(deny(libc::EAFNOSUPPORT));
+        filter.push(allow);
+
+        let program = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &program,
+            )
+        };
+        if res != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENOSYS) || err.raw_os_error() == Some(libc::EINVAL)
+            {
+                debug!("seccomp unavailable: {err}");
+                return Ok(false);
+            }
+            return Err(err).context("cannot install the seccomp filter");
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::thumbnail::{ThumbSource, VALIDATED};
+
+// Upper bounds of the buckets thumbnail generation times are sorted in, the
+// last bucket holding anything slower.
+pub const DURATION_BUCKETS: [Duration; 8] = [
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+    Duration::from_secs(30),
+];
+
+// Counters of everything process_item() did since startup. Relaxed atomics
+// only: they are read for reporting, never to make decisions.
+#[derive(Default)]
+pub struct Stats {
+    cache_hits: AtomicU64,
+    reused: AtomicU64,
+    generated: AtomicU64,
+    failed: AtomicU64,
+    cancelled: AtomicU64,
+    generation_nanos: AtomicU64,
+    generation_buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+}
+
+pub static STATS: Stats = Stats {
+    cache_hits: AtomicU64::new(0),
+    reused: AtomicU64::new(0),
+    generated: AtomicU64::new(0),
+    failed: AtomicU64::new(0),
+    cancelled: AtomicU64::new(0),
+    generation_nanos: AtomicU64::new(0),
+    generation_buckets: [const { AtomicU64::new(0) }; DURATION_BUCKETS.len() + 1],
+};
+
+impl Stats {
+    pub fn record(&self, source: ThumbSource, elapsed: Duration) {
+        let counter = match source {
+            ThumbSource::Cache => &self.cache_hits,
+            ThumbSource::SharedRepository | ThumbSource::LegacyCache => &self.reused,
+            ThumbSource::Generated => {
+                self.generation_nanos.fetch_add(
+                    elapsed.as_nanos().try_into().unwrap_or(u64::MAX),
+                    Ordering::Relaxed,
+                );
+                let bucket = DURATION_BUCKETS
+                    .iter()
+                    .position(|&bound| elapsed <= bound)
+                    .unwrap_or(DURATION_BUCKETS.len());
+                self.generation_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+                &self.generated
+            }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Medias skipped because their request was dequeued or the daemon
+    // stopped, which process_item() never sees.
+    pub fn record_cancelled(&self, count: u64) {
+        self.cancelled.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let (validation_hits, validation_misses) = VALIDATED.stats();
+        StatsSnapshot {
+            cache_hits: load(&self.cache_hits),
+            reused: load(&self.reused),
+            generated: load(&self.generated),
+            failed: load(&self.failed),
+            cancelled: load(&self.cancelled),
+            generation_time: Duration::from_nanos(load(&self.generation_nanos)),
+            generation_buckets: self.generation_buckets.iter().map(load).collect(),
+            validation_hits,
+            validation_misses,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct StatsSnapshot {
+    pub cache_hits: u64,
+    // Found in a shared repository or the legacy cache.
+    pub reused: u64,
+    pub generated: u64,
+    pub failed: u64,
+    pub cancelled: u64,
+    // Spent generating thumbnails, in total.
+    pub generation_time: Duration,
+    // Number of thumbnails generated within each of DURATION_BUCKETS, then
+    // slower.
+    pub generation_buckets: Vec<u64>,
+    // Cache hits that did not need to read the thumbnail, and those that did.
+    pub validation_hits: u64,
+    pub validation_misses: u64,
+}
+
+impl StatsSnapshot {
+    // Generation time under which `quantile` of the thumbnails were
+    // generated, rounded up to a bucket bound. None if no thumbnail was
+    // generated, or if that is slower than the last bound.
+    pub fn generation_quantile(&self, quantile: f64) -> Option<Duration> {
+        let total: u64 = self.generation_buckets.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let target = (quantile * total as f64).ceil() as u64;
+        let mut seen = 0;
+        for (i, count) in self.generation_buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target.max(1) {
+                return DURATION_BUCKETS.get(i).copied();
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} cache hit(s), {} reused, {} generated in {:?}, {} failed, {} cancelled",
+            self.cache_hits,
+            self.reused,
+            self.generated,
+            self.generation_time,
+            self.failed,
+            self.cancelled
+        )?;
+        let quantile = |q| match self.generation_quantile(q) {
+            Some(bound) => format!("≤{bound:?}"),
+            None if self.generated == 0 => "n/a".to_owned(),
+            None => format!(">{:?}", DURATION_BUCKETS[DURATION_BUCKETS.len() - 1]),
+        };
+        write!(
+            f,
+            " (median {}, p95 {}); {} of {} validation(s) without reading",
+            quantile(0.5),
+            quantile(0.95),
+            self.validation_hits,
+            self.validation_hits + self.validation_misses
+        )
+    }
+}
@@ -0,0 +1,808 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, anyhow};
+use image::{DynamicImage, ImageFormat};
+use itertools::Itertools;
+use log::{debug, warn};
+
+use crate::{
+    cache,
+    dbus::{ErrorCode, MediaRef, ThumbFlavor},
+    exec::{self, ExecMode, ExecThumbnailer},
+    jpeg,
+    redact::{DisplayPath, DisplayUri},
+    stats::STATS,
+    validated::ValidatedCache,
+    xdg::{
+        FAIL_APP_NAME, NotARegularFile, ThumbFsMeta, ThumbFullMeta, ThumbPixelFormat,
+        ThumbWriteOptions, create_private_dir_all, destination_filename, fail_dir, fail_filename,
+        get_thumb_full_metadata, get_thumb_original_metadata, legacy_cache_destination,
+        shared_cache_dir, shared_uri, sync_dir, temp_filename, write_fail_marker,
+        write_thumb_with_original_metadata,
+    },
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub mime_sniffing: bool,
+    pub legacy_cache_fallback: bool,
+    pub shared_repositories: bool,
+    pub strict_validation: bool,
+    // Originals larger than this are not thumbnailed, None for no limit.
+    pub max_source_bytes: Option<u64>,
+    // Number of up-to-date thumbnails to remember, 0 to always read them.
+    pub validation_cache_size: usize,
+    pub write: ThumbWriteOptions,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            mime_sniffing: false,
+            legacy_cache_fallback: false,
+            shared_repositories: false,
+            strict_validation: false,
+            max_source_bytes: None,
+            validation_cache_size: 4096,
+            write: ThumbWriteOptions::default(),
+        }
+    }
+}
+
+// Shared by all callers of process_item().
+pub static VALIDATED: LazyLock<ValidatedCache> = LazyLock::new(ValidatedCache::default);
+
+// URI schemes and MIME types reported by GetSupported.
+pub const SUPPORTED_SCHEMES: &[&str] = &["file"];
+
+pub fn supported_mime_types() -> Vec<String> {
+    let external = exec::get()
+        .into_iter()
+        .flat_map(|thumbnailers| thumbnailers.iter())
+        .flat_map(|thumbnailer| thumbnailer.mime_types.iter().cloned());
+    ImageFormat::all()
+        .map(|f| f.to_mime_type().to_owned())
+        .chain(["image/vnd.microsoft.icon".to_owned()])
+        .chain(external)
+        .unique()
+        .collect()
+}
+
+// Whether medias of this MIME type can be thumbnailed here, by a decoder or a
+// .thumbnailer file, rather than only by sniffing their content.
+pub fn handles_mime_type(mime_type: &str) -> bool {
+    format_from_mime_type(mime_type).is_some()
+        || exec::get().is_some_and(|thumbnailers| thumbnailers.find(mime_type).is_some())
+}
+
+// Historical or non-standard MIME types that clients still send.
+const MIME_TYPE_ALIASES: &[(&str, ImageFormat)] = &[
+    ("image/vnd.microsoft.icon", ImageFormat::Ico),
+    ("image/jpg", ImageFormat::Jpeg),
+    ("image/pjpeg", ImageFormat::Jpeg),
+    ("image/x-png", ImageFormat::Png),
+    ("image/apng", ImageFormat::Png),
+    ("image/x-ms-bmp", ImageFormat::Bmp),
+    ("image/x-bmp", ImageFormat::Bmp),
+    ("image/x-targa", ImageFormat::Tga),
+    ("image/x-tga", ImageFormat::Tga),
+    ("image/x-portable-anymap", ImageFormat::Pnm),
+    ("image/x-portable-bitmap", ImageFormat::Pnm),
+    ("image/x-portable-graymap", ImageFormat::Pnm),
+    ("image/x-portable-pixmap", ImageFormat::Pnm),
+];
+
+fn format_from_mime_type(mime_type: &str) -> Option<ImageFormat> {
+    let mime_type = mime_type.trim().to_ascii_lowercase();
+    MIME_TYPE_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == mime_type)
+        .map(|(_, format)| *format)
+        .or_else(|| ImageFormat::from_mime_type(&mime_type))
+}
+
+fn is_image_mime_type(mime_type: &str) -> bool {
+    mime_type
+        .trim()
+        .get(..6)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("image/"))
+}
+
+fn guess_format(path: &Path, media: &MediaRef, options: &Options) -> anyhow::Result<ImageFormat> {
+    let sniffed = image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .format();
+    // Clients may send no MIME type, or a useless one like application/octet-stream.
+    if options.mime_sniffing && format_from_mime_type(&media.mime_type).is_none() {
+        return sniffed.ok_or_else(|| {
+            anyhow!(
+                "unrecognized file content (MIME type '{}')",
+                &media.mime_type
+            )
+            .context(ErrorCode::Unsupported)
+        });
+    }
+    let declared = match (ImageFormat::from_path(path), sniffed) {
+        (Ok(format), _) => format,
+        // Any image MIME type is enough to trust the content over a missing
+        // or unknown extension.
+        (Err(_), Some(format)) if is_image_mime_type(&media.mime_type) => return Ok(format),
+        (Err(err), _) => return Err(err.into()),
+    };
+    // Renamed files would otherwise fail to decode. Formats without magic
+    // bytes are not recognized, and taken at their word.
+    match sniffed {
+        Some(format) if format != declared => {
+            debug!(
+                "{} looks like {format:?}, not {declared:?}",
+                DisplayUri(&media.uri)
+            );
+            Ok(format)
+        }
+        _ => Ok(declared),
+    }
+}
+
+type Decoder = fn(&Path, ImageFormat) -> anyhow::Result<DynamicImage>;
+
+fn decode_jpeg(path: &Path, _: ImageFormat) -> anyhow::Result<DynamicImage> {
+    jpeg::open(path)
+}
+
+fn decode_generic(path: &Path, format: ImageFormat) -> anyhow::Result<DynamicImage> {
+    Ok(image::load(
+        std::io::BufReader::new(std::fs::File::open(path)?),
+        format,
+    )?)
+}
+
+// Tried in order, the next one only if the previous one could not make sense
+// of the content: the more lenient generic decoder reads some JPEGs ours
+// rejects.
+fn decoders(format: ImageFormat) -> &'static [(&'static str, Decoder)] {
+    match format {
+        ImageFormat::Jpeg => &[("jpeg", decode_jpeg), ("image", decode_generic)],
+        _ => &[("image", decode_generic)],
+    }
+}
+
+// Names of the decoders tried for a MIME type, in order, aliases included.
+// None if it is not supported.
+pub fn decoders_for(mime_type: &str) -> Option<Vec<&'static str>> {
+    let format = format_from_mime_type(mime_type)?;
+    Some(decoders(format).iter().map(|(name, _)| *name).collect())
+}
+
+// Decoders tried for each supported MIME type, in order.
+pub fn decoders_by_mime_type() -> Vec<(String, Vec<&'static str>)> {
+    supported_mime_types()
+        .into_iter()
+        .filter_map(|mime_type| {
+            let names = decoders_for(&mime_type)?;
+            Some((mime_type, names))
+        })
+        .collect()
+}
+
+fn open_image(path: &Path, format: ImageFormat) -> anyhow::Result<DynamicImage> {
+    let decoders = decoders(format);
+    let mut last_err = None;
+    for (name, decode) in decoders {
+        match decode(path, format) {
+            Ok(im) => return Ok(im),
+            // Reading the file would fail again.
+            Err(err) if err.chain().any(|cause| cause.is::<std::io::Error>()) => return Err(err),
+            Err(err) => {
+                debug!("{name} decoder failed on {}: {err:#}", DisplayPath(path));
+                last_err = Some(err);
+            }
+        }
+    }
+    let err = last_err.expect("at least one decoder");
+    if decoders.len() == 1 {
+        return Err(err);
+    }
+    let names = decoders.iter().map(|(name, _)| name).join(", ");
+    Err(err.context(format!("no decoder could read the file (tried {names})")))
+}
+
+// Only keeps an alpha channel if there is one to begin with.
+fn thumb_pixels(im: DynamicImage) -> DynamicImage {
+    if im.color().has_alpha() {
+        DynamicImage::ImageRgba8(im.into_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(im.into_rgb8())
+    }
+}
+
+// Expects images from thumb_pixels().
+pub fn write_image(
+    path: &Path,
+    meta: &ThumbFullMeta,
+    thumb: &DynamicImage,
+    options: &ThumbWriteOptions,
+) -> anyhow::Result<()> {
+    let format = if thumb.color().has_alpha() {
+        ThumbPixelFormat::Rgba8
+    } else {
+        ThumbPixelFormat::Rgb8
+    };
+    write_thumb_with_original_metadata(
+        path,
+        meta,
+        thumb.width(),
+        thumb.height(),
+        format,
+        thumb.as_bytes(),
+        options,
+    )
+}
+
+// Decodes the original and scales it down to fit in a `dimension` square.
+pub fn render_thumbnail(
+    original_path: &Path,
+    original_meta: ThumbFsMeta,
+    media: &MediaRef,
+    options: &Options,
+    dimension: u32,
+) -> anyhow::Result<(ThumbFullMeta, DynamicImage)> {
+    let format = guess_format(original_path, media, options)?;
+    let im = open_image(original_path, format)?;
+    let thumb = thumb_pixels(im.thumbnail(dimension, dimension));
+    Ok((
+        ThumbFullMeta::from(original_meta, im.width(), im.height())
+            .with_mime_type(Some(format.to_mime_type().to_owned())),
+        thumb,
+    ))
+}
+
+// Renders with our decoders or an external thumbnailer, depending on how
+// those were set up.
+fn render(
+    original_path: &Path,
+    original_meta: ThumbFsMeta,
+    media: &MediaRef,
+    options: &Options,
+    dimension: u32,
+) -> anyhow::Result<(ThumbFullMeta, DynamicImage)> {
+    let external = exec::get().and_then(|thumbnailers| {
+        let thumbnailer = thumbnailers.find(&media.mime_type)?;
+        Some((thumbnailer, thumbnailers.mode, thumbnailers.timeout))
+    });
+    let Some((thumbnailer, mode, timeout)) = external else {
+        return render_thumbnail(original_path, original_meta, media, options, dimension);
+    };
+    let run = |original_meta| {
+        render_external(
+            thumbnailer,
+            timeout,
+            original_path,
+            original_meta,
+            media,
+            dimension,
+        )
+    };
+    if mode == ExecMode::Preferred {
+        return run(original_meta);
+    }
+    match render_thumbnail(
+        original_path,
+        original_meta.clone(),
+        media,
+        options,
+        dimension,
+    ) {
+        Err(err) if is_unsupported(&err) => {
+            debug!(
+                "{err:#}, falling back to the {} thumbnailer",
+                thumbnailer.name
+            );
+            run(original_meta)
+        }
+        rendered => rendered,
+    }
+}
+
+fn render_external(
+    thumbnailer: &ExecThumbnailer,
+    timeout: Duration,
+    original_path: &Path,
+    original_meta: ThumbFsMeta,
+    media: &MediaRef,
+    dimension: u32,
+) -> anyhow::Result<(ThumbFullMeta, DynamicImage)> {
+    let im = thumbnailer
+        .run(original_path, &media.uri, dimension, timeout)
+        .context(ErrorCode::Failed)?;
+    // Not all of them honor the size.
+    let im = if im.width() > dimension || im.height() > dimension {
+        im.thumbnail(dimension, dimension)
+    } else {
+        im
+    };
+    // The dimensions of the original are unknown.
+    Ok((
+        ThumbFullMeta::from(original_meta, 0, 0).with_mime_type(Some(media.mime_type.clone())),
+        thumb_pixels(im),
+    ))
+}
+
+fn is_unsupported(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ErrorCode>() == Some(&ErrorCode::Unsupported)
+        || err.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref::<image::ImageError>(),
+                Some(image::ImageError::Unsupported(_))
+            )
+        })
+}
+
+// Where the thumbnail returned by process_item() comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ThumbSource {
+    // Already up to date in the cache.
+    Cache,
+    SharedRepository,
+    LegacyCache,
+    Generated,
+}
+
+impl ThumbSource {
+    pub fn is_reused(self) -> bool {
+        self != ThumbSource::Generated
+    }
+}
+
+pub fn process_item(
+    id: usize,
+    cache_dir: &Path,
+    flavor: &ThumbFlavor,
+    options: &Options,
+    media: &MediaRef,
+) -> Result<(PathBuf, ThumbSource), ThumbError> {
+    let start = Instant::now();
+    let result = thumbnail_media(id, cache_dir, flavor, options, media);
+    match &result {
+        Ok((_, source)) => STATS.record(*source, start.elapsed()),
+        Err(_) => STATS.record_failure(),
+    }
+    Ok(result?)
+}
+
+fn thumbnail_media(
+    id: usize,
+    cache_dir: &Path,
+    flavor: &ThumbFlavor,
+    options: &Options,
+    media: &MediaRef,
+) -> anyhow::Result<(PathBuf, ThumbSource)> {
+    let uri = url::Url::parse(&media.uri).context(ErrorCode::Unsupported)?;
+    if !SUPPORTED_SCHEMES.contains(&uri.scheme()) {
+        return Err(
+            anyhow!("unsupported URI scheme '{}'", uri.scheme()).context(ErrorCode::Unsupported)
+        );
+    }
+    let original_path = match uri.to_file_path() {
+        Ok(path) => path,
+        Err(_) => return Err(anyhow!("not a local file").context(ErrorCode::Unsupported)),
+    };
+    if is_in_cache(&original_path, cache_dir) {
+        return Err(
+            anyhow!("{} is in the thumbnail cache", DisplayPath(&original_path))
+                .context(ErrorCode::IsThumbnail),
+        );
+    }
+    let root_cache_dir = cache_dir;
+    let cache_dir = flavor.cache_path(cache_dir);
+    let original_meta = ThumbFsMeta::from(&media.uri, &original_path)?;
+    let thumb_path = destination_filename(&cache_dir, &media.uri);
+    // Bail cheaply if already on disk & no changes.
+    let remembered = VALIDATED.get(&thumb_path);
+    let existing = match remembered.clone() {
+        Some(meta) => Ok(meta),
+        None => get_thumb_original_metadata(&thumb_path),
+    };
+    if let Ok(existing_original_meta) = existing {
+        if existing_original_meta.matches(&original_meta, options.strict_validation) {
+            debug!("cache hit for {}", DisplayUri(&media.uri));
+            if remembered.is_none() {
+                VALIDATED.insert(
+                    &thumb_path,
+                    existing_original_meta,
+                    options.validation_cache_size,
+                );
+            }
+            if let Err(err) = cache::touch(&thumb_path) {
+                debug!("cannot touch {thumb_path:?}: {err}");
+            }
+            return Ok((thumb_path, ThumbSource::Cache));
+        }
+    }
+    VALIDATED.remove(&thumb_path);
+    let shared = options
+        .shared_repositories
+        .then(|| shared_repository(&original_path, root_cache_dir, flavor))
+        .flatten();
+    if let Some((shared_dir, shared_uri)) = &shared {
+        let shared_meta = ThumbFsMeta {
+            uri: shared_uri.clone(),
+            ..original_meta.clone()
+        };
+        let shared_path = destination_filename(shared_dir, shared_uri);
+        if let Some(meta) = get_thumb_full_metadata(&shared_path)
+            .ok()
+            .filter(|meta| meta.fs == shared_meta)
+        {
+            debug!("shared repository hit for {}", DisplayUri(&media.uri));
+            // Not ours to fix if it is corrupt, so generate one instead.
+            match image::open(&shared_path) {
+                Ok(img) => {
+                    // Applications only look for it in the cache.
+                    let thumb = thumb_pixels(img);
+                    let meta = ThumbFullMeta {
+                        fs: original_meta,
+                        ..meta
+                    };
+                    let path =
+                        write_thumbnail(&cache_dir, &media.uri, id, &meta, &thumb, &options.write)
+                            .context(ErrorCode::SaveFailed)?;
+                    return Ok((path, ThumbSource::SharedRepository));
+                }
+                Err(err) => debug!("cannot decode {}: {err}", DisplayPath(&shared_path)),
+            }
+        }
+    }
+    let fail_path = fail_filename(root_cache_dir, FAIL_APP_NAME, &media.uri);
+    if get_thumb_original_metadata(&fail_path).is_ok_and(|meta| meta == original_meta) {
+        return Err(
+            anyhow!("failed before, not retrying until the file changes")
+                .context(ErrorCode::InvalidFormat),
+        );
+    }
+    if options.legacy_cache_fallback {
+        let legacy_dir = flavor.cache_path(&legacy_cache_destination()?);
+        if let Some(path) =
+            cache::adopt_legacy_thumbnail(&legacy_dir, &cache_dir, &original_meta, id)
+                .context(ErrorCode::SaveFailed)?
+        {
+            return Ok((path, ThumbSource::LegacyCache));
+        }
+    }
+    if let (Some(limit), Some(size)) = (options.max_source_bytes, original_meta.size) {
+        if size > limit {
+            return Err(SourceTooLarge { size, limit }.into());
+        }
+    }
+    let (original_meta, thumb) = match render(
+        &original_path,
+        original_meta.clone(),
+        media,
+        options,
+        flavor.dimension(),
+    ) {
+        Ok(rendered) => rendered,
+        Err(err) => {
+            if is_decode_error(&err) {
+                if let Err(err) = write_fail_marker(&fail_path, &original_meta) {
+                    warn!("cannot write {fail_path:?}: {err:#}");
+                }
+            }
+            return Err(err);
+        }
+    };
+    if let Some((shared_dir, shared_uri)) = &shared {
+        let shared_meta = ThumbFullMeta {
+            fs: ThumbFsMeta {
+                uri: shared_uri.clone(),
+                ..original_meta.fs.clone()
+            },
+            ..original_meta.clone()
+        };
+        // The medium may well be read-only.
+        if let Err(err) = std::fs::create_dir_all(shared_dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| {
+                write_thumbnail(
+                    shared_dir,
+                    shared_uri,
+                    id,
+                    &shared_meta,
+                    &thumb,
+                    &options.write,
+                )
+            })
+        {
+            debug!(
+                "cannot write to shared repository {}: {err:#}",
+                DisplayPath(shared_dir)
+            );
+        }
+    }
+    let path = write_thumbnail(
+        &cache_dir,
+        &media.uri,
+        id,
+        &original_meta,
+        &thumb,
+        &options.write,
+    )
+    .context(ErrorCode::SaveFailed)?;
+    Ok((path, ThumbSource::Generated))
+}
+
+// Shared repository directory and name of the original in it, if the original
+// is on another file system than the cache, e.g. on removable media.
+fn shared_repository(
+    original_path: &Path,
+    cache_dir: &Path,
+    flavor: &ThumbFlavor,
+) -> Option<(PathBuf, String)> {
+    let device = |path: &Path| std::fs::metadata(path).ok().map(|meta| meta.dev());
+    if device(original_path)? == device(cache_dir)? {
+        return None;
+    }
+    Some((
+        flavor.cache_path(&shared_cache_dir(original_path)?),
+        shared_uri(original_path)?,
+    ))
+}
+
+// Atomically writes the thumbnail of `uri` in `dir`.
+fn write_thumbnail(
+    dir: &Path,
+    uri: &str,
+    id: usize,
+    meta: &ThumbFullMeta,
+    thumb: &DynamicImage,
+    write: &ThumbWriteOptions,
+) -> anyhow::Result<PathBuf> {
+    let temp_thumb_path = temp_filename(dir, uri, id);
+    let thumb_path = destination_filename(dir, uri);
+    let written = write_image(&temp_thumb_path, meta, thumb, write)
+        .and_then(|()| Ok(std::fs::rename(&temp_thumb_path, &thumb_path)?));
+    if let Err(err) = written {
+        // Likely truncated, e.g. on a full disk.
+        _ = std::fs::remove_file(&temp_thumb_path);
+        return Err(err);
+    }
+    if write.durable {
+        sync_dir(dir)?;
+    }
+    Ok(thumb_path)
+}
+
+// Whether the original is broken, as opposed to unsupported or unreadable,
+// which may change without the original itself changing.
+fn is_decode_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<zune_jpeg::errors::DecodeErrors>()
+            || matches!(
+                cause.downcast_ref::<image::ImageError>(),
+                Some(image::ImageError::Decoding(_) | image::ImageError::Limits(_))
+            )
+    })
+}
+
+#[derive(Debug)]
+pub struct SourceTooLarge {
+    pub size: u64,
+    pub limit: u64,
+}
+
+impl fmt::Display for SourceTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "source larger than limit ({} > {})",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for SourceTooLarge {}
+
+// Why a media could not be thumbnailed. Each variant keeps the underlying
+// error chain for logging.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ThumbError {
+    NotFound(anyhow::Error),
+    // Unsupported URI scheme, MIME type or file type.
+    Unsupported(anyhow::Error),
+    CorruptSource(anyhow::Error),
+    // Larger than Options::max_source_bytes.
+    TooLarge(anyhow::Error),
+    CacheWriteFailed(anyhow::Error),
+    // The cache's file system is full or read-only, so writing any other
+    // thumbnail would fail the same way.
+    CacheUnwritable(anyhow::Error),
+    // The original is itself in the thumbnail cache.
+    IsThumbnail(anyhow::Error),
+    // Reading the original failed for another reason, e.g. permissions.
+    Io(anyhow::Error),
+    Other(anyhow::Error),
+}
+
+impl ThumbError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ThumbError::Unsupported(_) | ThumbError::TooLarge(_) => ErrorCode::Unsupported,
+            ThumbError::NotFound(_) | ThumbError::CorruptSource(_) | ThumbError::Io(_) => {
+                ErrorCode::InvalidFormat
+            }
+            ThumbError::CacheWriteFailed(_) | ThumbError::CacheUnwritable(_) => {
+                ErrorCode::SaveFailed
+            }
+            ThumbError::IsThumbnail(_) => ErrorCode::IsThumbnail,
+            ThumbError::Other(_) => ErrorCode::Failed,
+        }
+    }
+
+    fn inner(&self) -> &anyhow::Error {
+        match self {
+            ThumbError::NotFound(err)
+            | ThumbError::Unsupported(err)
+            | ThumbError::CorruptSource(err)
+            | ThumbError::TooLarge(err)
+            | ThumbError::CacheWriteFailed(err)
+            | ThumbError::CacheUnwritable(err)
+            | ThumbError::IsThumbnail(err)
+            | ThumbError::Io(err)
+            | ThumbError::Other(err) => err,
+        }
+    }
+}
+
+// The whole chain, so that log lines and D-Bus error messages say what
+// actually went wrong.
+impl fmt::Display for ThumbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.inner())
+    }
+}
+
+impl std::error::Error for ThumbError {}
+
+impl From<anyhow::Error> for ThumbError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast_ref::<ErrorCode>() {
+            Some(ErrorCode::Unsupported) => return ThumbError::Unsupported(err),
+            Some(ErrorCode::SaveFailed) if is_cache_unwritable(&err) => {
+                return ThumbError::CacheUnwritable(err);
+            }
+            Some(ErrorCode::SaveFailed) => return ThumbError::CacheWriteFailed(err),
+            Some(ErrorCode::InvalidFormat) => return ThumbError::CorruptSource(err),
+            Some(ErrorCode::IsThumbnail) => return ThumbError::IsThumbnail(err),
+            Some(_) => return ThumbError::Other(err),
+            None => {}
+        }
+        if is_decode_error(&err) {
+            return ThumbError::CorruptSource(err);
+        }
+        for cause in err.chain() {
+            if cause.is::<NotARegularFile>() {
+                return ThumbError::Unsupported(err);
+            }
+            if cause.is::<SourceTooLarge>() {
+                return ThumbError::TooLarge(err);
+            }
+            if let Some(image::ImageError::Unsupported(_)) = cause.downcast_ref() {
+                return ThumbError::Unsupported(err);
+            }
+            if let Some(image::ImageError::IoError(io)) = cause.downcast_ref() {
+                return io_error(io.kind(), err);
+            }
+            if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+                return io_error(io.kind(), err);
+            }
+        }
+        ThumbError::Other(err)
+    }
+}
+
+fn is_cache_unwritable(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        // Which only exposes the I/O error through the deprecated cause().
+        let io = match cause.downcast_ref() {
+            Some(png::EncodingError::IoError(io)) => Some(io),
+            _ => cause.downcast_ref::<std::io::Error>(),
+        };
+        io.is_some_and(|io| {
+            matches!(
+                io.kind(),
+                std::io::ErrorKind::StorageFull
+                    | std::io::ErrorKind::QuotaExceeded
+                    | std::io::ErrorKind::ReadOnlyFilesystem
+            )
+        })
+    })
+}
+
+fn io_error(kind: std::io::ErrorKind, err: anyhow::Error) -> ThumbError {
+    match kind {
+        std::io::ErrorKind::NotFound => ThumbError::NotFound(err),
+        _ => ThumbError::Io(err),
+    }
+}
+
+// Cache roots resolved so far, by the path they were given as: callers may
+// each use their own cache directory. Roots that do not exist yet are resolved
+// again until they do.
+static CACHE_ROOTS: LazyLock<Mutex<HashMap<PathBuf, PathBuf>>> = LazyLock::new(Default::default);
+
+fn resolve_cache_root(dir: &Path) -> Option<PathBuf> {
+    if let Some(root) = CACHE_ROOTS.lock().unwrap().get(dir) {
+        return Some(root.clone());
+    }
+    let root = dir.canonicalize().ok()?;
+    CACHE_ROOTS
+        .lock()
+        .unwrap()
+        .insert(dir.to_owned(), root.clone());
+    Some(root)
+}
+
+// Whether thumbnailing `path` would thumbnail a thumbnail, or a failure
+// marker. Resolved, so that links into the cache are caught too; an original
+// that cannot be resolved fails later with a better error.
+fn is_in_cache(path: &Path, cache_dir: &Path) -> bool {
+    let Ok(path) = path.canonicalize() else {
+        return false;
+    };
+    std::iter::once(cache_dir.to_owned())
+        .chain(legacy_cache_destination().ok())
+        .filter_map(|dir| resolve_cache_root(&dir))
+        .any(|root| path.starts_with(root))
+}
+
+// Distinguishes the temporary files of concurrent calls below.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Thumbnails a local file into `cache_dir`, returning the thumbnail path.
+// Up-to-date thumbnails are reused, so calling this repeatedly is cheap.
+pub fn thumbnail_file(
+    cache_dir: &Path,
+    path: &Path,
+    flavor: &ThumbFlavor,
+    options: &Options,
+) -> Result<PathBuf, ThumbError> {
+    let uri = std::path::absolute(path)
+        .ok()
+        .and_then(|path| url::Url::from_file_path(path).ok())
+        .ok_or_else(|| {
+            ThumbError::Unsupported(anyhow!("cannot build a file:// URI for {}", path.display()))
+        })?;
+    thumbnail_uri(cache_dir, uri.as_str(), "", flavor, options)
+}
+
+// Same as thumbnail_file() for a file:// URI. The format is guessed from the
+// content when the MIME type is empty or unknown.
+pub fn thumbnail_uri(
+    cache_dir: &Path,
+    uri: &str,
+    mime_type: &str,
+    flavor: &ThumbFlavor,
+    options: &Options,
+) -> Result<PathBuf, ThumbError> {
+    create_private_dir_all(&flavor.cache_path(cache_dir))
+        .and_then(|()| create_private_dir_all(&fail_dir(cache_dir, FAIL_APP_NAME)))
+        .map_err(|err| ThumbError::from(anyhow::Error::from(err).context(ErrorCode::SaveFailed)))?;
+    let mut options = *options;
+    options.mime_sniffing |= mime_type.is_empty();
+    let media = MediaRef {
+        uri: uri.to_owned(),
+        mime_type: mime_type.to_owned(),
+    };
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    process_item(id, cache_dir, flavor, &options, &media).map(|(path, _)| path)
+}
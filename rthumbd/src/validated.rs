@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::xdg::ThumbFsMeta;
+
+// Thumbnails recently found up to date, along with the metadata read from
+// them, so that checking them again only takes a stat instead of parsing the
+// PNG. Thumbnails are replaced by renaming over them, so an entry is only
+// trusted as long as the thumbnail keeps the same inode.
+pub struct ValidatedCache {
+    shards: Vec<Mutex<HashMap<PathBuf, Entry>>>,
+    hasher: RandomState,
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entry {
+    meta: ThumbFsMeta,
+    dev: u64,
+    ino: u64,
+    last_use: u64,
+}
+
+const SHARDS: usize = 16;
+
+impl Default for ValidatedCache {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ValidatedCache {
+    fn shard(&self, thumb_path: &Path) -> &Mutex<HashMap<PathBuf, Entry>> {
+        &self.shards[self.hasher.hash_one(thumb_path) as usize % SHARDS]
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // The metadata the thumbnail was last validated against, if it was not
+    // replaced since.
+    pub fn get(&self, thumb_path: &Path) -> Option<ThumbFsMeta> {
+        let stat = std::fs::metadata(thumb_path).ok();
+        let mut shard = self.shard(thumb_path).lock().unwrap();
+        let found = match (shard.get_mut(thumb_path), stat) {
+            (Some(entry), Some(stat)) if entry.dev == stat.dev() && entry.ino == stat.ino() => {
+                entry.last_use = self.tick();
+                Some(entry.meta.clone())
+            }
+            (Some(_), _) => {
+                shard.remove(thumb_path);
+                None
+            }
+            (None, _) => None,
+        };
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    // Evicts the least recently used entries beyond `capacity`, 0 disabling
+    // the cache altogether.
+    pub fn insert(&self, thumb_path: &Path, meta: ThumbFsMeta, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        let Ok(stat) = std::fs::metadata(thumb_path) else {
+            return;
+        };
+        let mut shard = self.shard(thumb_path).lock().unwrap();
+        shard.insert(
+            thumb_path.to_owned(),
+            Entry {
+                meta,
+                dev: stat.dev(),
+                ino: stat.ino(),
+                last_use: self.tick(),
+            },
+        );
+        while shard.len() > capacity.div_ceil(SHARDS) {
+            let Some(oldest) = shard
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_use)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            shard.remove(&oldest);
+        }
+    }
+
+    pub fn remove(&self, thumb_path: &Path) {
+        self.shard(thumb_path).lock().unwrap().remove(thumb_path);
+    }
+
+    // Hits and misses so far.
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
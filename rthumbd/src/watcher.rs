@@ -0,0 +1,236 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsStr, OsString},
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use log::{debug, warn};
+use rthumbd::{
+    dbus::FlavorSet, redact::DisplayPath, thumbnail::VALIDATED, xdg::destination_filename,
+};
+
+// Writes, and replacements through renames, of files in the directory.
+const MASK: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_ONLYDIR;
+
+struct WatchedDir {
+    path: PathBuf,
+    // Files thumbnailed since the directory is watched; changes to others
+    // are ignored.
+    names: HashSet<OsString>,
+    last_use: u64,
+}
+
+#[derive(Default)]
+struct State {
+    dirs: HashMap<i32, WatchedDir>,
+    by_path: HashMap<PathBuf, i32>,
+    clock: u64,
+    // Set once inotify ran out of watches, to only warn once.
+    exhausted: bool,
+}
+
+// Removes the thumbnails of files that change after being thumbnailed, so
+// that clients trusting the cache without checking it do not show outdated
+// ones. Only the directories of the `max_dirs` most recently thumbnailed files
+// are watched, as inotify watches are a limited resource.
+pub struct Watcher {
+    fd: OwnedFd,
+    cache_dir: PathBuf,
+    flavors: FlavorSet,
+    max_dirs: usize,
+    state: Mutex<State>,
+}
+
+impl Watcher {
+    fn new(cache_dir: PathBuf, flavors: FlavorSet, max_dirs: usize) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            cache_dir,
+            flavors,
+            max_dirs,
+            state: Mutex::default(),
+        })
+    }
+
+    pub fn watch_uri(&self, uri: &str) {
+        if let Some(original) = url::Url::parse(uri)
+            .ok()
+            .and_then(|uri| uri.to_file_path().ok())
+        {
+            self.watch(&original);
+        }
+    }
+
+    fn watch(&self, original: &Path) {
+        let (Some(dir), Some(name)) = (original.parent(), original.file_name()) else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let now = state.clock;
+        if let Some(wd) = state.by_path.get(dir).copied() {
+            let watched = state.dirs.get_mut(&wd).unwrap();
+            watched.names.insert(name.to_owned());
+            watched.last_use = now;
+            return;
+        }
+        if state.dirs.len() >= self.max_dirs {
+            let oldest = state
+                .dirs
+                .iter()
+                .min_by_key(|(_, watched)| watched.last_use)
+                .map(|(&wd, _)| wd);
+            if let Some(wd) = oldest {
+                self.forget(&mut state, wd);
+                unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd) };
+            }
+        }
+        let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+            return;
+        };
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), MASK) };
+        if wd < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENOSPC) {
+                if !state.exhausted {
+                    warn!("out of inotify watches, not watching more directories: {err}");
+                    state.exhausted = true;
+                }
+            } else {
+                debug!("cannot watch {}: {err}", DisplayPath(dir));
+            }
+            return;
+        }
+        state.by_path.insert(dir.to_owned(), wd);
+        state.dirs.insert(
+            wd,
+            WatchedDir {
+                path: dir.to_owned(),
+                names: HashSet::from([name.to_owned()]),
+                last_use: now,
+            },
+        );
+    }
+
+    fn forget(&self, state: &mut State, wd: i32) {
+        if let Some(watched) = state.dirs.remove(&wd) {
+            state.by_path.remove(&watched.path);
+        }
+    }
+
+    fn read_events(&self) -> io::Result<()> {
+        // Aligned for inotify_event, and large enough for any single event.
+        let mut buf = vec![0u64; 4096 / size_of::<u64>()];
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr().cast(),
+                    buf.len() * size_of::<u64>(),
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            let bytes =
+                unsafe { std::slice::from_raw_parts(buf.as_ptr().cast::<u8>(), len as usize) };
+            let mut offset = 0;
+            while offset + size_of::<libc::inotify_event>() <= bytes.len() {
+                let event = unsafe {
+                    bytes
+                        .as_ptr()
+                        .add(offset)
+                        .cast::<libc::inotify_event>()
+                        .read_unaligned()
+                };
+                let name_start = offset + size_of::<libc::inotify_event>();
+                let name = &bytes[name_start..name_start + event.len as usize];
+                let name = OsStr::from_bytes(name.split(|&b| b == 0).next().unwrap_or_default());
+                offset = name_start + event.len as usize;
+                self.handle_event(event.wd, event.mask, name);
+            }
+        }
+    }
+
+    fn handle_event(&self, wd: i32, mask: u32, name: &OsStr) {
+        let mut state = self.state.lock().unwrap();
+        if mask & libc::IN_IGNORED != 0 {
+            // The directory is gone, or its watch was removed.
+            self.forget(&mut state, wd);
+            return;
+        }
+        let Some(watched) = state.dirs.get_mut(&wd) else {
+            return;
+        };
+        // Watched again if thumbnailed again.
+        if !watched.names.remove(name) {
+            return;
+        }
+        let original = watched.path.join(name);
+        drop(state);
+        self.invalidate(&original);
+    }
+
+    fn invalidate(&self, original: &Path) {
+        let Ok(uri) = url::Url::from_file_path(original) else {
+            return;
+        };
+        let mut removed = 0;
+        for flavor in self.flavors.iter() {
+            let thumb_path =
+                destination_filename(&flavor.cache_path(&self.cache_dir), uri.as_str());
+            VALIDATED.remove(&thumb_path);
+            if std::fs::remove_file(&thumb_path).is_ok() {
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            debug!(
+                "{} changed, removed {removed} thumbnail(s)",
+                DisplayPath(original)
+            );
+        }
+    }
+}
+
+static WATCHER: OnceLock<Arc<Watcher>> = OnceLock::new();
+
+// Starts watching; thumbnailed files are not watched until this is called. On
+// failure, thumbnails are only found outdated once asked for again.
+pub fn init(cache_dir: PathBuf, flavors: FlavorSet, max_dirs: usize) -> anyhow::Result<()> {
+    let watcher = Arc::new(Watcher::new(cache_dir, flavors, max_dirs)?);
+    std::thread::Builder::new()
+        .name("rthumbd-watch".to_owned())
+        .spawn({
+            let watcher = watcher.clone();
+            move || {
+                if let Err(err) = watcher.read_events() {
+                    warn!("no longer watching for changes: {err}");
+                }
+            }
+        })?;
+    _ = WATCHER.set(watcher);
+    Ok(())
+}
+
+pub fn get() -> Option<&'static Watcher> {
+    WATCHER.get().map(|watcher| &**watcher)
+}
@@ -0,0 +1,273 @@
+use std::{
+    cell::RefCell,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, anyhow};
+use log::{debug, warn};
+use rthumbd::{
+    dbus::{ErrorCode, FlavorSet, MediaRef, ThumbFlavor},
+    stats::STATS,
+    thumbnail::{Options, ThumbError, ThumbSource},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{CacheBackoff, Outcome, panic_message, run_item};
+
+// Frames are a little-endian length followed by a TOML document; anything
+// larger is a protocol error.
+const MAX_FRAME_LEN: u32 = 1 << 20;
+
+#[derive(Serialize, Deserialize)]
+struct Job {
+    id: usize,
+    flavor: String,
+    uri: String,
+    mime_type: String,
+}
+
+// Tagged inside, as a TOML document cannot be a bare enum.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "kebab-case")]
+enum Done {
+    Ready {
+        path: PathBuf,
+        source: ThumbSource,
+        elapsed: Duration,
+    },
+    Failed {
+        code: ErrorCode,
+        message: String,
+        #[serde(default)]
+        cache_unwritable: bool,
+    },
+}
+
+fn write_frame(writer: &mut impl Write, value: &impl Serialize) -> anyhow::Result<()> {
+    let text = toml::to_string(value)?;
+    let len = u32::try_from(text.len())
+        .ok()
+        .filter(|&len| len <= MAX_FRAME_LEN)
+        .ok_or_else(|| anyhow!("frame too large"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(text.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+// None on a clean end of stream.
+fn read_frame<T: for<'de> Deserialize<'de>>(reader: &mut impl Read) -> anyhow::Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(anyhow!("frame too large: {len} bytes"));
+    }
+    let mut text = vec![0; len as usize];
+    reader.read_exact(&mut text)?;
+    Ok(Some(toml::from_str(std::str::from_utf8(&text)?)?))
+}
+
+// Body of the hidden worker command: thumbnails the medias read from stdin one
+// at a time, writing each outcome to stdout, until stdin is closed.
+pub fn serve(cache_dir: &Path, flavors: &FlavorSet, options: &Options) -> anyhow::Result<()> {
+    let mut stdin = BufReader::new(io::stdin().lock());
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    while let Some(job) = read_frame::<Job>(&mut stdin)? {
+        let Some(flavor) = flavors.get(&job.flavor) else {
+            let done = Done::Failed {
+                code: ErrorCode::UnsupportedFlavor,
+                message: format!("unknown flavor '{}'", job.flavor),
+                cache_unwritable: false,
+            };
+            write_frame(&mut stdout, &done)?;
+            continue;
+        };
+        let media = MediaRef {
+            uri: job.uri,
+            mime_type: job.mime_type,
+        };
+        let start = Instant::now();
+        let done = match run_item(job.id, cache_dir, flavor, options, &media) {
+            Ok(Ok((path, source))) => Done::Ready {
+                path,
+                source,
+                elapsed: start.elapsed(),
+            },
+            Ok(Err(err)) => Done::Failed {
+                code: err.code(),
+                message: format!("{err:#}"),
+                cache_unwritable: matches!(err, ThumbError::CacheUnwritable(_)),
+            },
+            Err(payload) => Done::Failed {
+                code: ErrorCode::Failed,
+                message: format!("thumbnailer panicked: {}", panic_message(&*payload)),
+                cache_unwritable: false,
+            },
+        };
+        write_frame(&mut stdout, &done)?;
+    }
+    Ok(())
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    // Filled by a thread of its own, so that waiting for an outcome can time
+    // out; disconnected once the worker exits.
+    done_rx: mpsc::Receiver<anyhow::Result<Done>>,
+}
+
+impl Worker {
+    // With the daemon's own arguments, so that it loads the same
+    // configuration.
+    fn spawn() -> anyhow::Result<Self> {
+        let mut child = Command::new(std::env::current_exe()?)
+            .args(std::env::args_os().skip(1))
+            .arg("worker")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("cannot start a worker")?;
+        let stdin = child.stdin.take().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let (done_tx, done_rx) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("rthumbd-worker-reader".to_owned())
+            .spawn(move || {
+                loop {
+                    match read_frame(&mut stdout) {
+                        Ok(Some(done)) => _ = done_tx.send(Ok(done)),
+                        Ok(None) => break,
+                        Err(err) => {
+                            _ = done_tx.send(Err(err));
+                            break;
+                        }
+                    }
+                }
+            })?;
+        debug!("started worker {}", child.id());
+        Ok(Self {
+            child,
+            stdin,
+            done_rx,
+        })
+    }
+
+    // Why the worker is gone, once its output was closed.
+    fn exit_status(&mut self) -> String {
+        match self.child.wait() {
+            Ok(status) => status.to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        _ = self.child.kill();
+        _ = self.child.wait();
+    }
+}
+
+thread_local! {
+    // One per pool thread, started on first use and again after a crash.
+    static WORKER: RefCell<Option<Worker>> = const { RefCell::new(None) };
+}
+
+// Same as processing the media in this process, except that a crash or a
+// timeout only kills the worker, which is started again for the next media.
+pub fn run(
+    id: usize,
+    flavor: &ThumbFlavor,
+    media: MediaRef,
+    timeout: Option<Duration>,
+    cache_backoff: &CacheBackoff,
+) -> Outcome {
+    let failed = |uri, message| {
+        STATS.record_failure();
+        Outcome::Error {
+            uri,
+            code: ErrorCode::Failed,
+            message,
+        }
+    };
+    WORKER.with_borrow_mut(|slot| {
+        let worker = match slot {
+            Some(worker) => worker,
+            None => match Worker::spawn() {
+                Ok(worker) => slot.insert(worker),
+                Err(err) => return failed(media.uri, format!("{err:#}")),
+            },
+        };
+        let job = Job {
+            id,
+            flavor: flavor.name().to_owned(),
+            uri: media.uri.clone(),
+            mime_type: media.mime_type,
+        };
+        let done = match write_frame(&mut worker.stdin, &job) {
+            Ok(()) => match timeout {
+                Some(timeout) => worker.done_rx.recv_timeout(timeout),
+                None => worker
+                    .done_rx
+                    .recv()
+                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            },
+            Err(_) => Err(mpsc::RecvTimeoutError::Disconnected),
+        };
+        let outcome = match done {
+            Ok(Ok(Done::Ready {
+                path,
+                source,
+                elapsed,
+            })) => {
+                STATS.record(source, elapsed);
+                return Outcome::Ready {
+                    uri: media.uri,
+                    path,
+                    source,
+                };
+            }
+            Ok(Ok(Done::Failed {
+                code,
+                message,
+                cache_unwritable,
+            })) => {
+                STATS.record_failure();
+                if cache_unwritable {
+                    cache_backoff.trip(&message);
+                }
+                return Outcome::Error {
+                    uri: media.uri,
+                    code,
+                    message,
+                };
+            }
+            Ok(Err(err)) => failed(media.uri, format!("worker misbehaved: {err:#}")),
+            Err(mpsc::RecvTimeoutError::Timeout) => failed(
+                media.uri,
+                format!(
+                    "thumbnailing timed out after {:?}",
+                    timeout.unwrap_or_default()
+                ),
+            ),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                let status = worker.exit_status();
+                warn!("worker {} died: {status}", worker.child.id());
+                failed(media.uri, format!("thumbnailer crashed ({status})"))
+            }
+        };
+        // Killed and waited for on drop.
+        *slot = None;
+        outcome
+    })
+}
@@ -1,52 +1,306 @@
 use std::{
     fmt,
-    ops::Deref,
-    os::linux::fs::MetadataExt,
+    io::{BufReader, Read, Seek, SeekFrom},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, OpenOptionsExt},
     path::{Path, PathBuf},
 };
 
 use anyhow::{Context, anyhow};
-use png::text_metadata::TEXtChunk;
+use png::text_metadata::{ITXtChunk, TEXtChunk};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ThumbFsMeta {
     pub uri: String,
-    pub mtime_nsec: f64,
-    pub size: u64,
+    pub mtime_secs: i64,
+    // 0 if unknown, as thumbnails usually only record whole seconds.
+    pub mtime_nanos: u32,
+    // None if unknown, as Thumb::Size is optional.
+    pub size: Option<u64>,
+    // Only compared in strict mode, catching originals replaced by copies
+    // with the same size and mtime.
+    pub ino: Option<u64>,
+    pub ctime: Option<i64>,
+}
+
+#[derive(Debug)]
+pub struct NotARegularFile(pub &'static str);
+
+impl fmt::Display for NotARegularFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not a regular file: {}", self.0)
+    }
+}
+
+impl std::error::Error for NotARegularFile {}
+
+fn check_regular_file(file_type: std::fs::FileType) -> Result<(), NotARegularFile> {
+    if file_type.is_file() {
+        Ok(())
+    } else if file_type.is_dir() {
+        Err(NotARegularFile("directory"))
+    } else if file_type.is_fifo() {
+        Err(NotARegularFile("FIFO"))
+    } else if file_type.is_socket() {
+        Err(NotARegularFile("socket"))
+    } else if file_type.is_char_device() {
+        Err(NotARegularFile("character device"))
+    } else if file_type.is_block_device() {
+        Err(NotARegularFile("block device"))
+    } else {
+        Err(NotARegularFile("unknown file type"))
+    }
 }
 
 impl ThumbFsMeta {
     pub fn from(uri: &str, path: &Path) -> anyhow::Result<Self> {
+        // Follows symlinks, so links to regular files are fine. This must
+        // happen before anything opens the file: reading a FIFO blocks forever.
         let file_meta = std::fs::metadata(path)?;
-        let mtime_nsec = file_meta.st_mtime() as f64 + file_meta.st_mtime_nsec() as f64 / 1e9;
-        let size = file_meta.st_size();
+        check_regular_file(file_meta.file_type())?;
         Ok(Self {
             uri: uri.to_owned(),
-            mtime_nsec,
-            size,
+            mtime_secs: file_meta.mtime(),
+            mtime_nanos: file_meta.mtime_nsec() as u32,
+            size: Some(file_meta.size()),
+            ino: Some(file_meta.ino()),
+            ctime: Some(file_meta.ctime()),
         })
     }
+
+    // Like ==, but also compares the inode and ctime if `strict` and both
+    // sides know them.
+    pub fn matches(&self, other: &Self, strict: bool) -> bool {
+        self == other
+            && (!strict
+                || same_if_known(self.ino, other.ino) && same_if_known(self.ctime, other.ctime))
+    }
+}
+
+fn same_if_known<T: PartialEq>(a: Option<T>, b: Option<T>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    }
 }
 
 impl PartialEq for ThumbFsMeta {
     fn eq(&self, other: &Self) -> bool {
-        self.uri == other.uri
-            && self.mtime_nsec == other.mtime_nsec
-            && (self.size == 0 || other.size == 0 || self.size == other.size)
+        (self.uri == other.uri || canonical_uri(&self.uri) == canonical_uri(&other.uri))
+            && self.mtime_secs == other.mtime_secs
+            // Older versions wrote microseconds, through a lossy f64.
+            && (self.mtime_nanos == 0
+                || other.mtime_nanos == 0
+                || self.mtime_nanos.abs_diff(other.mtime_nanos) <= 1000)
+            && same_if_known(self.size, other.size)
     }
 }
 
-#[derive(Debug)]
+// Written to the Software chunk of thumbnails by default.
+pub const SOFTWARE: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone)]
 pub struct ThumbFullMeta {
+    // Dimensions of the original, 0 if unknown.
     pub width: u32,
     pub height: u32,
     pub fs: ThumbFsMeta,
+    pub mime_type: Option<String>,
+    pub software: Option<String>,
+    // Additional text chunks, such as Thumb::Document::Pages.
+    pub extra: Vec<(String, String)>,
+}
+
+// Chunks written from ThumbFullMeta fields, which extra ones cannot replace.
+const RESERVED_KEYWORDS: &[&str] = &[
+    "Thumb::URI",
+    "Thumb::MTime",
+    "Thumb::Size",
+    "Thumb::X-Inode",
+    "Thumb::X-CTime",
+    "Thumb::Image::Width",
+    "Thumb::Image::Height",
+    "Thumb::Mimetype",
+    "Software",
+];
+
+fn check_extra_keyword(keyword: &str) -> anyhow::Result<()> {
+    let len = keyword.chars().count();
+    if !(1..=79).contains(&len) || keyword.chars().any(|c| c as u32 > 0xff) {
+        return Err(anyhow!(
+            "invalid chunk keyword {keyword:?}: must be 1 to 79 Latin-1 characters"
+        ));
+    }
+    if RESERVED_KEYWORDS.contains(&keyword) {
+        return Err(anyhow!("chunk keyword {keyword:?} is reserved"));
+    }
+    Ok(())
 }
 
 impl ThumbFullMeta {
     pub fn from(fs: ThumbFsMeta, width: u32, height: u32) -> Self {
-        Self { width, height, fs }
+        Self {
+            width,
+            height,
+            fs,
+            mime_type: None,
+            software: Some(SOFTWARE.to_owned()),
+            extra: Vec::new(),
+        }
+    }
+
+    pub fn with_extra(mut self, keyword: impl Into<String>, text: impl Into<String>) -> Self {
+        self.extra.push((keyword.into(), text.into()));
+        self
+    }
+
+    pub fn with_mime_type(mut self, mime_type: Option<String>) -> Self {
+        self.mime_type = mime_type;
+        self
+    }
+
+    pub fn with_software(mut self, software: Option<String>) -> Self {
+        self.software = software;
+        self
+    }
+
+    // Whether this very program wrote it, in any version.
+    pub fn is_ours(&self) -> bool {
+        self.software
+            .as_deref()
+            .is_some_and(|software| software.starts_with(concat!(env!("CARGO_PKG_NAME"), "/")))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbPixelFormat {
+    Rgb8,
+    Rgba8,
+}
+
+impl ThumbPixelFormat {
+    pub fn channels(&self) -> usize {
+        match self {
+            ThumbPixelFormat::Rgb8 => 3,
+            ThumbPixelFormat::Rgba8 => 4,
+        }
+    }
+
+    fn color_type(&self) -> png::ColorType {
+        match self {
+            ThumbPixelFormat::Rgb8 => png::ColorType::Rgb,
+            ThumbPixelFormat::Rgba8 => png::ColorType::Rgba,
+        }
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    #[default]
+    Fast,
+    Default,
+    Best,
+}
+
+// Thumbnails are disposable and get written while someone waits for them,
+// hence favoring speed over size by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThumbWriteOptions {
+    pub compression: PngCompression,
+    // Pick a filter per row rather than always the same one: smaller, slower.
+    pub adaptive_filter: bool,
+    // Flush thumbnails to disk before renaming them into place, so that a
+    // crash cannot leave truncated ones behind.
+    pub durable: bool,
+    // Thumbnails no larger than this many pixels across are written with a
+    // palette of up to 256 colors when that looks close enough, 0 to never.
+    pub palette_max_size: u32,
+}
+
+// Average squared error per channel above which a palette is not used.
+const MAX_PALETTE_ERROR: f64 = 24.0;
+
+struct Quantized {
+    palette: Vec<u8>,
+    // Alpha of each palette entry, if the thumbnail has an alpha channel.
+    trns: Option<Vec<u8>>,
+    indices: Vec<u8>,
+}
+
+// Exact when there are few enough colors to begin with, as with icons or
+// screenshots.
+fn quantize(format: ThumbPixelFormat, data: &[u8]) -> Option<Quantized> {
+    let rgba: Vec<[u8; 4]> = match format {
+        ThumbPixelFormat::Rgba8 => data
+            .chunks_exact(4)
+            .map(|px| [px[0], px[1], px[2], px[3]])
+            .collect(),
+        ThumbPixelFormat::Rgb8 => data
+            .chunks_exact(3)
+            .map(|px| [px[0], px[1], px[2], 0xff])
+            .collect(),
+    };
+    let (colors, indices) = exact_palette(&rgba).or_else(|| approximate_palette(&rgba))?;
+    Some(Quantized {
+        palette: colors.iter().flat_map(|c| [c[0], c[1], c[2]]).collect(),
+        trns: (format == ThumbPixelFormat::Rgba8).then(|| colors.iter().map(|c| c[3]).collect()),
+        indices,
+    })
+}
+
+fn exact_palette(rgba: &[[u8; 4]]) -> Option<(Vec<[u8; 4]>, Vec<u8>)> {
+    let mut colors = Vec::new();
+    let mut index_of = std::collections::HashMap::new();
+    let indices = rgba
+        .iter()
+        .map(|px| {
+            let next = colors.len();
+            let index = *index_of.entry(*px).or_insert(next);
+            if index == next {
+                colors.push(*px);
+            }
+            u8::try_from(index).ok()
+        })
+        .collect::<Option<_>>()?;
+    Some((colors, indices))
+}
+
+fn approximate_palette(rgba: &[[u8; 4]]) -> Option<(Vec<[u8; 4]>, Vec<u8>)> {
+    let quantizer = color_quant::NeuQuant::new(10, 256, rgba.as_flattened());
+    let mut error = 0.0;
+    let indices: Vec<u8> = rgba
+        .iter()
+        .map(|px| {
+            let index = quantizer.index_of(px);
+            let color = quantizer.lookup(index).unwrap_or_default();
+            // The color of transparent pixels does not matter.
+            let alpha = px[3] as f64 / 255.0;
+            error += (0..3)
+                .map(|i| (alpha * (px[i] as f64 - color[i] as f64)).powi(2))
+                .sum::<f64>()
+                + (px[3] as f64 - color[3] as f64).powi(2);
+            index as u8
+        })
+        .collect();
+    if error / (rgba.len().max(1) * 4) as f64 > MAX_PALETTE_ERROR {
+        return None;
     }
+    let colors = quantizer
+        .color_map_rgba()
+        .chunks_exact(4)
+        .map(|c| [c[0], c[1], c[2], c[3]])
+        .collect();
+    Some((colors, indices))
 }
 
 pub fn write_thumb_with_original_metadata(
@@ -54,29 +308,223 @@ pub fn write_thumb_with_original_metadata(
     meta: &ThumbFullMeta,
     thumb_width: u32,
     thumb_height: u32,
+    format: ThumbPixelFormat,
     data: &[u8],
+    options: &ThumbWriteOptions,
 ) -> anyhow::Result<()> {
+    for (keyword, _) in &meta.extra {
+        check_extra_keyword(keyword)?;
+    }
+    let expected = thumb_width as usize * thumb_height as usize * format.channels();
+    if data.len() != expected {
+        return Err(anyhow!(
+            "{thumb_width}x{thumb_height} {format:?} thumbnail needs {expected} bytes, got {}",
+            data.len()
+        ));
+    }
+    // Thumbnails reveal what the originals look like.
     let f = std::fs::OpenOptions::new()
         .write(true)
         .truncate(true)
         .create(true)
+        .mode(0o600)
         .open(path)
-        .with_context(|| "open")?;
-    let mut encoder = png::Encoder::new(f, thumb_width, thumb_height);
-    encoder.set_color(png::ColorType::Rgb);
+        .context("cannot create the thumbnail file")?;
+    let quantized = (options.palette_max_size > 0
+        && thumb_width.max(thumb_height) <= options.palette_max_size)
+        .then(|| quantize(format, data))
+        .flatten();
+    let mut encoder = png::Encoder::new(&f, thumb_width, thumb_height);
+    let data = match &quantized {
+        Some(quantized) => {
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_palette(quantized.palette.as_slice());
+            if let Some(trns) = &quantized.trns {
+                encoder.set_trns(trns.as_slice());
+            }
+            &quantized.indices
+        }
+        None => {
+            encoder.set_color(format.color_type());
+            data
+        }
+    };
     encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(match options.compression {
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Default,
+        PngCompression::Best => png::Compression::Best,
+    });
+    encoder.set_adaptive_filter(if options.adaptive_filter {
+        png::AdaptiveFilterType::Adaptive
+    } else {
+        png::AdaptiveFilterType::NonAdaptive
+    });
     let mut writer = encoder.write_header()?;
-    writer.write_text_chunk(&TEXtChunk::new("Thumb::URI", &meta.fs.uri))?;
-    writer.write_text_chunk(&TEXtChunk::new(
+    write_text(&mut writer, "Thumb::URI", &meta.fs.uri)?;
+    write_text(
+        &mut writer,
         "Thumb::MTime",
-        format!("{:.6}", meta.fs.mtime_nsec),
-    ))?;
-    writer.write_text_chunk(&TEXtChunk::new("Thumb::Size", format!("{}", meta.fs.size)))?;
+        format!("{}", meta.fs.mtime_secs),
+    )?;
+    if let Some(size) = meta.fs.size {
+        write_text(&mut writer, "Thumb::Size", format!("{size}"))?;
+    }
+    if let Some(ino) = meta.fs.ino {
+        write_text(&mut writer, "Thumb::X-Inode", format!("{ino}"))?;
+    }
+    if let Some(ctime) = meta.fs.ctime {
+        write_text(&mut writer, "Thumb::X-CTime", format!("{ctime}"))?;
+    }
+    if meta.width > 0 && meta.height > 0 {
+        write_text(
+            &mut writer,
+            "Thumb::Image::Width",
+            format!("{}", meta.width),
+        )?;
+        write_text(
+            &mut writer,
+            "Thumb::Image::Height",
+            format!("{}", meta.height),
+        )?;
+    }
+    if let Some(mime_type) = &meta.mime_type {
+        write_text(&mut writer, "Thumb::Mimetype", mime_type)?;
+    }
+    if let Some(software) = &meta.software {
+        write_text(&mut writer, "Software", software)?;
+    }
+    for (keyword, text) in &meta.extra {
+        write_text(&mut writer, keyword, text)?;
+    }
     writer.write_image_data(data)?;
+    writer.finish()?;
+    if options.durable {
+        f.sync_all()?;
+    }
     Ok(())
 }
 
+// Makes renames in `dir` survive a crash.
+pub fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+// Text that is not Latin-1 goes to an iTXt chunk, which holds UTF-8.
+fn write_text<W: std::io::Write>(
+    writer: &mut png::Writer<W>,
+    keyword: &str,
+    text: impl AsRef<str>,
+) -> anyhow::Result<()> {
+    let text = text.as_ref();
+    if text.chars().all(|c| c as u32 <= 0xff) {
+        writer.write_text_chunk(&TEXtChunk::new(keyword, text))?;
+    } else {
+        writer.write_text_chunk(&ITXtChunk::new(keyword, text))?;
+    }
+    Ok(())
+}
+
+// Parses integer seconds, as the specification says, or seconds with a
+// fractional part, as older versions wrote. Fractions are parsed as decimals
+// rather than floats, which cannot hold nanoseconds.
+fn parse_mtime(text: &str) -> Option<(i64, u32)> {
+    let (secs, fraction) = text.trim().split_once('.').unwrap_or((text.trim(), ""));
+    let secs = secs.parse::<i64>().ok()?;
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let digits = &fraction[..fraction.len().min(9)];
+    let nanos = format!("{digits:0<9}").parse::<u32>().ok()?;
+    Some((secs, nanos))
+}
+
+// Also fails on truncated thumbnails, e.g. left behind by a crash, which
+// would otherwise have valid metadata but never display.
 pub fn get_thumb_original_metadata(path: &Path) -> anyhow::Result<ThumbFsMeta> {
+    let mut file = std::fs::File::open(path)?;
+    let mut end = [0; 12];
+    file.seek(SeekFrom::End(-(end.len() as i64)))
+        .and_then(|_| file.read_exact(&mut end))
+        .ok()
+        .filter(|()| end == PNG_END)
+        .ok_or(anyhow!("truncated thumbnail"))?;
+    file.rewind()?;
+    if let Some(meta) = read_original_metadata_fast(file) {
+        return Ok(meta);
+    }
+    Ok(get_thumb_full_metadata(path)?.fs)
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+// An empty IEND chunk, with its CRC.
+const PNG_END: [u8; 12] = [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82];
+// Much larger than any text chunk a thumbnailer would write.
+const MAX_TEXT_CHUNK_LEN: usize = 64 * 1024;
+
+// Walks the chunks up to the image data, only reading tEXt ones, as cache
+// hits are checked far more often than thumbnails are written. Returns None
+// whenever anything is unusual, including compressed or UTF-8 text, leaving
+// it to get_thumb_full_metadata() to make sense of the file or report why not.
+fn read_original_metadata_fast(file: std::fs::File) -> Option<ThumbFsMeta> {
+    let mut reader = BufReader::new(file);
+    let mut signature = [0; 8];
+    reader.read_exact(&mut signature).ok()?;
+    if signature != PNG_SIGNATURE {
+        return None;
+    }
+    let mut uri = None;
+    let mut mtime = None;
+    let mut size = None;
+    let mut ino = None;
+    let mut ctime = None;
+    loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header).ok()?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = &header[4..];
+        match kind {
+            b"IDAT" | b"IEND" => break,
+            b"zTXt" | b"iTXt" => return None,
+            b"tEXt" if length <= MAX_TEXT_CHUNK_LEN => {}
+            b"tEXt" => return None,
+            _ => {
+                reader.seek_relative(length as i64 + 4).ok()?;
+                continue;
+            }
+        }
+        let mut data = vec![0; length + 4];
+        reader.read_exact(&mut data).ok()?;
+        let (data, crc) = data.split_at(length);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(kind);
+        hasher.update(data);
+        if hasher.finalize().to_be_bytes() != crc {
+            return None;
+        }
+        let nul = data.iter().position(|&b| b == 0)?;
+        let text: String = data[nul + 1..].iter().map(|&b| b as char).collect();
+        match &data[..nul] {
+            b"Thumb::URI" => _ = uri.get_or_insert(text),
+            b"Thumb::MTime" => mtime = mtime.or_else(|| parse_mtime(&text)),
+            b"Thumb::Size" => size = size.or_else(|| text.parse::<u64>().ok()),
+            b"Thumb::X-Inode" => ino = ino.or_else(|| text.parse::<u64>().ok()),
+            b"Thumb::X-CTime" => ctime = ctime.or_else(|| text.parse::<i64>().ok()),
+            _ => {}
+        }
+    }
+    let (mtime_secs, mtime_nanos) = mtime?;
+    Some(ThumbFsMeta {
+        uri: uri?,
+        mtime_secs,
+        mtime_nanos,
+        size,
+        ino,
+        ctime,
+    })
+}
+
+pub fn get_thumb_full_metadata(path: &Path) -> anyhow::Result<ThumbFullMeta> {
     let decoder = png::Decoder::new(
         std::fs::OpenOptions::new()
             .read(true)
@@ -84,32 +532,123 @@ pub fn get_thumb_original_metadata(path: &Path) -> anyhow::Result<ThumbFsMeta> {
             .with_context(|| "open")?,
     );
     let mut uri = None;
-    let mut mtime_nsec = None;
+    let mut mtime = None;
     let mut size = None;
+    let mut ino = None;
+    let mut ctime = None;
+    let mut width = None;
+    let mut height = None;
+    let mut mime_type = None;
+    let mut software = None;
+    let mut extra: Vec<(String, String)> = Vec::new();
     let info_reader = decoder.read_info()?;
-    let png::Info {
-        uncompressed_latin1_text,
-        ..
-    } = info_reader.info();
-    for chunk in uncompressed_latin1_text {
-        match chunk.keyword.deref() {
-            "Thumb::URI" => uri = Some(chunk.text.clone()),
-            "Thumb::MTime" => mtime_nsec = chunk.text.parse::<f64>().ok(),
-            "Thumb::Size" => size = chunk.text.parse::<u64>().ok(),
+    let info = info_reader.info();
+    // Other thumbnailers may compress their metadata or store it as UTF-8.
+    // Undecodable chunks are skipped, and the first of each key wins.
+    let chunks = info
+        .uncompressed_latin1_text
+        .iter()
+        .map(|chunk| (chunk.keyword.as_str(), Some(chunk.text.clone())))
+        .chain(
+            info.compressed_latin1_text
+                .iter()
+                .map(|chunk| (chunk.keyword.as_str(), chunk.get_text().ok())),
+        )
+        .chain(
+            info.utf8_text
+                .iter()
+                .map(|chunk| (chunk.keyword.as_str(), chunk.get_text().ok())),
+        );
+    for (keyword, text) in chunks {
+        let Some(text) = text else {
+            continue;
+        };
+        match keyword {
+            "Thumb::URI" => _ = uri.get_or_insert(text),
+            "Thumb::MTime" => mtime = mtime.or_else(|| parse_mtime(&text)),
+            "Thumb::Size" => size = size.or_else(|| text.parse::<u64>().ok()),
+            "Thumb::X-Inode" => ino = ino.or_else(|| text.parse::<u64>().ok()),
+            "Thumb::X-CTime" => ctime = ctime.or_else(|| text.parse::<i64>().ok()),
+            "Thumb::Image::Width" => width = width.or_else(|| text.parse::<u32>().ok()),
+            "Thumb::Image::Height" => height = height.or_else(|| text.parse::<u32>().ok()),
+            "Thumb::Mimetype" => _ = mime_type.get_or_insert(text),
+            "Software" => _ = software.get_or_insert(text),
+            _ if keyword.starts_with("Thumb::") && extra.iter().all(|(k, _)| k != keyword) => {
+                extra.push((keyword.to_owned(), text))
+            }
             _ => continue,
         }
     }
-    Ok(ThumbFsMeta {
-        uri: uri.ok_or(anyhow!("missing uri"))?,
-        mtime_nsec: mtime_nsec.ok_or(anyhow!("missing mtime_nsec"))?,
-        size: size.unwrap_or(0),
+    let (mtime_secs, mtime_nanos) = mtime.ok_or(anyhow!("missing mtime"))?;
+    Ok(ThumbFullMeta {
+        width: width.unwrap_or(0),
+        height: height.unwrap_or(0),
+        fs: ThumbFsMeta {
+            uri: uri.ok_or(anyhow!("missing uri"))?,
+            mtime_secs,
+            mtime_nanos,
+            size,
+            ino,
+            ctime,
+        },
+        mime_type,
+        software,
+        extra,
     })
 }
 
+// Cache directories are only accessible to their owner.
+pub fn create_private_dir_all(path: &Path) -> std::io::Result<()> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)
+}
+
 pub fn destination_filename(dir: &Path, uri: &str) -> PathBuf {
     dir.join(format!("{}.png", uri_hash(uri)))
 }
 
+// Shared thumbnail repository for files in the same directory as `original`,
+// which travels with them, e.g. on removable media.
+pub fn shared_cache_dir(original: &Path) -> Option<PathBuf> {
+    Some(original.parent()?.join(".sh_thumbnails"))
+}
+
+// Thumbnails in shared repositories are named after the original's URI
+// relative to its directory, that is its escaped file name.
+pub fn shared_uri(original: &Path) -> Option<String> {
+    let uri = url::Url::from_file_path(original).ok()?;
+    Some(uri.path_segments()?.next_back()?.to_owned())
+}
+
+// Name under fail/ of this thumbnailer's failure markers.
+pub const FAIL_APP_NAME: &str = "rthumbd";
+
+pub fn fail_dir(cache_dir: &Path, appname: &str) -> PathBuf {
+    cache_dir.join("fail").join(appname)
+}
+
+// Marks that `appname` could not thumbnail `uri`, so that it is not retried
+// until the original changes.
+pub fn fail_filename(cache_dir: &Path, appname: &str, uri: &str) -> PathBuf {
+    destination_filename(&fail_dir(cache_dir, appname), uri)
+}
+
+// Failure markers are 1x1 thumbnails carrying the original's metadata.
+pub fn write_fail_marker(path: &Path, meta: &ThumbFsMeta) -> anyhow::Result<()> {
+    let meta = ThumbFullMeta::from(meta.clone(), 0, 0);
+    write_thumb_with_original_metadata(
+        path,
+        &meta,
+        1,
+        1,
+        ThumbPixelFormat::Rgb8,
+        &[0, 0, 0],
+        &ThumbWriteOptions::default(),
+    )
+}
+
 pub fn temp_filename(dir: &Path, uri: &str, id: usize) -> PathBuf {
     dir.join(format!("{}.tmp{}", uri_hash(uri), id))
 }
@@ -124,8 +663,94 @@ pub fn cache_destination() -> anyhow::Result<PathBuf> {
     }
 }
 
+// Where thumbnails went before the specification followed the XDG base
+// directories, and where some applications still look for them.
+pub fn legacy_cache_destination() -> anyhow::Result<PathBuf> {
+    std::env::var("HOME")
+        .map(|path| PathBuf::from(path).join(".thumbnails"))
+        .map_err(|_| anyhow!("HOME is unset"))
+}
+
+pub fn config_home() -> anyhow::Result<PathBuf> {
+    if let Ok(path) = std::env::var("XDG_CONFIG_HOME") {
+        Ok(PathBuf::from(path))
+    } else if let Ok(path) = std::env::var("HOME") {
+        Ok(PathBuf::from(path).join(".config"))
+    } else {
+        Err(anyhow!("both XDG_CONFIG_HOME and HOME are unset"))
+    }
+}
+
+pub fn data_home() -> anyhow::Result<PathBuf> {
+    if let Ok(path) = std::env::var("XDG_DATA_HOME") {
+        Ok(PathBuf::from(path))
+    } else if let Ok(path) = std::env::var("HOME") {
+        Ok(PathBuf::from(path).join(".local").join("share"))
+    } else {
+        Err(anyhow!("both XDG_DATA_HOME and HOME are unset"))
+    }
+}
+
+// Most important first.
+pub fn data_dirs() -> Vec<PathBuf> {
+    match std::env::var("XDG_DATA_DIRS") {
+        Ok(dirs) if !dirs.is_empty() => std::env::split_paths(&dirs).collect(),
+        _ => vec!["/usr/local/share".into(), "/usr/share".into()],
+    }
+}
+
+// Clients spell the same URI in different ways, yet should share thumbnails.
+// This spelling is the one GLib uses, so that clients hashing their URIs
+// themselves find thumbnails where they expect them.
 fn uri_hash(uri: &str) -> String {
-    format!("{}", HexSlice(&md5::compute(uri).to_vec()))
+    format!("{}", HexSlice(&md5::compute(canonical_uri(uri)).to_vec()))
+}
+
+// Escapes what must be and only that, with uppercase hex digits, and drops
+// default ports, empty fragments and the trailing slash of file:// paths.
+// URIs that do not parse are returned as is.
+pub fn canonical_uri(uri: &str) -> String {
+    let Ok(mut url) = url::Url::parse(uri) else {
+        return uri.to_owned();
+    };
+    if url.fragment() == Some("") {
+        url.set_fragment(None);
+    }
+    if url.scheme() == "file" && url.path().len() > 1 && url.path().ends_with('/') {
+        let path = url.path().trim_end_matches('/').to_owned();
+        url.set_path(if path.is_empty() { "/" } else { &path });
+    }
+    normalize_percent_encoding(url.as_str())
+}
+
+// Decodes escaped unreserved characters and uppercases the other escapes.
+fn normalize_percent_encoding(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut normalized = String::with_capacity(uri.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                normalized.push(byte as char);
+                i += 3;
+            }
+            Some(byte) => {
+                normalized.push_str(&format!("%{byte:02X}"));
+                i += 3;
+            }
+            None => {
+                let c = uri[i..].chars().next().unwrap();
+                normalized.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    normalized
 }
 
 struct HexSlice<'a>(&'a [u8]);
@@ -138,3 +763,103 @@ impl fmt::Display for HexSlice<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_uri_escapes_only_what_must_be() {
+        assert_eq!(canonical_uri("file:///a b.png"), "file:///a%20b.png");
+        assert_eq!(canonical_uri("file:///a%20b.png"), "file:///a%20b.png");
+        assert_eq!(canonical_uri("file:///%C3%A9.png"), "file:///%C3%A9.png");
+        assert_eq!(canonical_uri("file:///é.png"), "file:///%C3%A9.png");
+    }
+
+    #[test]
+    fn canonical_uri_uppercases_escapes() {
+        assert_eq!(canonical_uri("file:///a%2fb.png"), "file:///a%2Fb.png");
+        assert_eq!(canonical_uri("file:///%c3%a9.png"), "file:///%C3%A9.png");
+    }
+
+    #[test]
+    fn canonical_uri_decodes_unreserved() {
+        assert_eq!(canonical_uri("file:///%41.png"), "file:///A.png");
+        assert_eq!(
+            canonical_uri("file:///%7e%2D%5f%2E.png"),
+            "file:///~-_..png"
+        );
+    }
+
+    #[test]
+    fn canonical_uri_drops_default_ports() {
+        assert_eq!(canonical_uri("http://host:80/a.png"), "http://host/a.png");
+        assert_eq!(
+            canonical_uri("https://host:443/a.png"),
+            "https://host/a.png"
+        );
+        assert_eq!(
+            canonical_uri("http://host:8080/a.png"),
+            "http://host:8080/a.png"
+        );
+    }
+
+    #[test]
+    fn canonical_uri_drops_empty_fragments() {
+        assert_eq!(canonical_uri("file:///a.png#"), "file:///a.png");
+        assert_eq!(
+            canonical_uri("file:///a.png#page=2"),
+            "file:///a.png#page=2"
+        );
+    }
+
+    #[test]
+    fn canonical_uri_drops_trailing_slashes_of_files() {
+        assert_eq!(canonical_uri("file:///home/me/dir/"), "file:///home/me/dir");
+        assert_eq!(
+            canonical_uri("file:///home/me/dir//"),
+            "file:///home/me/dir"
+        );
+        assert_eq!(canonical_uri("file:///"), "file:///");
+        assert_eq!(canonical_uri("http://host/dir/"), "http://host/dir/");
+    }
+
+    #[test]
+    fn canonical_uri_handles_other_schemes() {
+        assert_eq!(
+            canonical_uri("HTTPS://Example.COM/a%7eb.png?q=%2f#"),
+            "https://example.com/a~b.png?q=%2F"
+        );
+        assert_eq!(canonical_uri("trash:///a b.png"), "trash:///a%20b.png");
+        assert_eq!(
+            canonical_uri("smb://host/share/a.png"),
+            "smb://host/share/a.png"
+        );
+    }
+
+    #[test]
+    fn canonical_uri_passes_unparsable_through() {
+        for uri in [
+            "",
+            "a.png",
+            "/home/me/a b.png",
+            "http://[::1/a.png",
+            "http://host:99999/",
+        ] {
+            assert_eq!(canonical_uri(uri), uri);
+        }
+    }
+
+    #[test]
+    fn canonical_uri_is_idempotent() {
+        for uri in [
+            "file:///a b.png",
+            "file:///%41%2f/",
+            "https://Host:443/x#",
+            "a b",
+        ] {
+            let canonical = canonical_uri(uri);
+            assert_eq!(canonical_uri(&canonical), canonical);
+        }
+    }
+}